@@ -0,0 +1,15 @@
+; Shift-and-add multiplication of 0111 by 0011, leaving 21 in r3.
+        storei r0, 7        ; multiplicand
+        storei r1, 3        ; multiplier
+        storei r2, 0        ; product
+        storei r3, 4        ; bits left
+next:   copy r4, r1
+        andi r4, 1
+        jumpif r4, add      ; lowest multiplier bit set
+        jump shift
+add:    add r2, r2, r0
+shift:  shiftleft r0, 1
+        shiftright r1, 1
+        subtracti r3, 1
+        jumpif r3, next
+        copy r3, r2
@@ -0,0 +1,376 @@
+use crate::diagnostic::{Diagnostic, Span};
+use crate::execute::{Instruction, Label, Register, RegisterContent, Slot, TrapKind};
+use crate::link::{Labels, LinkError};
+use std::collections::HashMap;
+
+/// Turns the text format the disassembler prints back into a program.
+///
+/// Every line holds at most one instruction, written like `addi r4, 15`, and may start with any
+/// number of `name:` labels. Jumps go to a label by name or to an instruction index like `@7`,
+/// a label after the last instruction is the end of the program. Everything after a `;` is a
+/// comment.
+pub fn assemble(source: &str) -> Result<Vec<Instruction>, Vec<Diagnostic>> {
+    let mut assembler = Assembler::default();
+    let mut offset = 0;
+    for line in source.split_inclusive('\n') {
+        assembler.line(Piece::new(line, offset));
+        offset += line.len();
+    }
+    assembler.link()
+}
+
+/// A decimal number, or binary or hexadecimal with a `0b` or `0x` prefix. Anything that fits in
+/// the bits of a register goes, so `0xffffffff` is -1.
+pub fn number(word: &str) -> Option<RegisterContent> {
+    let (negative, word) = match word.strip_prefix('-') {
+        Some(word) => (true, word),
+        None => (false, word),
+    };
+    let value = if let Some(digits) = word.strip_prefix("0b") {
+        u32::from_str_radix(digits, 2)
+    } else if let Some(digits) = word.strip_prefix("0x") {
+        u32::from_str_radix(digits, 16)
+    } else {
+        word.parse()
+    };
+    value.ok().map(|value| {
+        let value = value as RegisterContent;
+        if negative {
+            value.wrapping_neg()
+        } else {
+            value
+        }
+    })
+}
+
+/// Part of the source and where it starts.
+#[derive(Debug, Copy, Clone)]
+struct Piece<'a> {
+    text: &'a str,
+    offset: usize,
+}
+
+impl<'a> Piece<'a> {
+    fn new(text: &'a str, offset: usize) -> Self {
+        Self { text, offset }
+    }
+
+    fn span(&self) -> Span {
+        Span::new(self.offset, self.text.len())
+    }
+
+    fn trim(self) -> Self {
+        let start = self.text.len() - self.text.trim_start().len();
+        Piece::new(self.text.trim(), self.offset + start)
+    }
+
+    /// The text before `index` and from it onwards.
+    fn split_at(self, index: usize) -> (Self, Self) {
+        let (before, after) = self.text.split_at(index);
+        (
+            Piece::new(before, self.offset),
+            Piece::new(after, self.offset + index),
+        )
+    }
+
+    /// The pieces between the `separator`s.
+    fn split(self, separator: char) -> impl Iterator<Item = Piece<'a>> {
+        let mut offset = self.offset;
+        self.text.split(separator).map(move |text| {
+            let piece = Piece::new(text, offset);
+            offset += text.len() + separator.len_utf8();
+            piece
+        })
+    }
+
+    fn is_name(&self) -> bool {
+        let mut chars = self.text.chars();
+        chars
+            .next()
+            .is_some_and(|first| first.is_ascii_alphabetic() || first == '_')
+            && chars.all(|rest| rest.is_ascii_alphanumeric() || rest == '_')
+    }
+}
+
+/// Where a jump goes, before labels are known.
+enum Target<'a> {
+    Index(Label),
+    Name(Piece<'a>),
+}
+
+/// The operands of one instruction, taken from left to right.
+struct Operands<'a> {
+    pieces: std::vec::IntoIter<Piece<'a>>,
+    /// Just past the last operand, for errors about missing ones.
+    end: Span,
+}
+
+impl<'a> Operands<'a> {
+    fn new(operands: Piece<'a>) -> Self {
+        let end = Span::new(operands.offset + operands.text.len(), 0);
+        let pieces: Vec<Piece> = if operands.text.is_empty() {
+            Vec::new()
+        } else {
+            operands.split(',').map(Piece::trim).collect()
+        };
+        Self {
+            pieces: pieces.into_iter(),
+            end,
+        }
+    }
+
+    fn next(&mut self, expected: &str) -> Result<Piece<'a>, Diagnostic> {
+        self.pieces
+            .next()
+            .ok_or_else(|| Diagnostic::new(format!("Expected {expected}"), self.end))
+    }
+
+    fn register(&mut self) -> Result<Register, Diagnostic> {
+        let expected = "a register like r3";
+        let piece = self.next(expected)?;
+        piece
+            .text
+            .strip_prefix('r')
+            .and_then(|register| register.parse().ok())
+            .ok_or_else(|| Diagnostic::new(format!("Expected {expected}"), piece.span()))
+    }
+
+    fn constant(&mut self) -> Result<RegisterContent, Diagnostic> {
+        let expected = "a number";
+        let piece = self.next(expected)?;
+        number(piece.text)
+            .ok_or_else(|| Diagnostic::new(format!("Expected {expected}"), piece.span()))
+    }
+
+    fn amount(&mut self) -> Result<u8, Diagnostic> {
+        let expected = "a shift amount from 0 to 255";
+        let piece = self.next(expected)?;
+        number(piece.text)
+            .and_then(|amount| amount.try_into().ok())
+            .ok_or_else(|| Diagnostic::new(format!("Expected {expected}"), piece.span()))
+    }
+
+    fn slot(&mut self) -> Result<Slot, Diagnostic> {
+        let expected = "a memory slot like [3]";
+        let piece = self.next(expected)?;
+        piece
+            .text
+            .strip_prefix('[')
+            .and_then(|slot| slot.strip_suffix(']'))
+            .and_then(|slot| slot.trim().parse().ok())
+            .ok_or_else(|| Diagnostic::new(format!("Expected {expected}"), piece.span()))
+    }
+
+    fn target(&mut self) -> Result<Target<'a>, Diagnostic> {
+        let expected = "a label or an instruction index like @7";
+        let piece = self.next(expected)?;
+        if let Some(index) = piece.text.strip_prefix('@') {
+            if let Ok(index) = index.parse() {
+                return Ok(Target::Index(index));
+            }
+        } else if piece.is_name() {
+            return Ok(Target::Name(piece));
+        }
+        Err(Diagnostic::new(
+            format!("Expected {expected}"),
+            piece.span(),
+        ))
+    }
+
+    fn trap(&mut self) -> Result<TrapKind, Diagnostic> {
+        let expected = "a kind of trap like divbyzero";
+        let piece = self.next(expected)?;
+        [TrapKind::DivisionByZero]
+            .into_iter()
+            .find(|kind| kind.mnemonic() == piece.text)
+            .ok_or_else(|| Diagnostic::new(format!("Expected {expected}"), piece.span()))
+    }
+
+    /// Errors if there are operands left over.
+    fn finish(&mut self) -> Result<(), Diagnostic> {
+        match self.pieces.next() {
+            Some(extra) => Err(Diagnostic::new("Unexpected operand", extra.span())),
+            None => Ok(()),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Assembler<'a> {
+    /// Assembled code, jumping to labels from `labels` until it is linked.
+    program: Vec<Instruction>,
+    labels: Labels,
+    names: HashMap<&'a str, Label>,
+    /// Where each named label was last defined, or first used if it never was.
+    places: HashMap<Label, Piece<'a>>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl<'a> Assembler<'a> {
+    fn line(&mut self, line: Piece<'a>) {
+        let code = line.split(';').next().unwrap_or(line);
+        let mut rest = code.trim();
+        while let Some(colon) = rest.text.find(':') {
+            let (name, after) = rest.split_at(colon);
+            let name = name.trim();
+            if !name.is_name() {
+                self.diagnostics
+                    .push(Diagnostic::new("Expected a label name", name.span()));
+                return;
+            }
+            let label = self.name(name);
+            self.places.insert(label, name);
+            self.labels.define(label, self.program.len());
+            rest = after.split_at(1).1.trim();
+        }
+        if rest.text.is_empty() {
+            return;
+        }
+
+        let (mnemonic, operands) = rest.split_at(
+            rest.text
+                .find(char::is_whitespace)
+                .unwrap_or(rest.text.len()),
+        );
+        match self.instruction(mnemonic, &mut Operands::new(operands.trim())) {
+            Ok(instruction) => self.program.push(instruction),
+            Err(diagnostic) => self.diagnostics.push(diagnostic),
+        }
+    }
+
+    fn instruction(
+        &mut self,
+        mnemonic: Piece<'a>,
+        operands: &mut Operands<'a>,
+    ) -> Result<Instruction, Diagnostic> {
+        let instruction = match mnemonic.text {
+            "negate" => Instruction::Negate {
+                register: operands.register()?,
+            },
+            "andi" => Instruction::AndI {
+                register: operands.register()?,
+                constant: operands.constant()?,
+            },
+            "jump" => Instruction::Jump {
+                instruction: self.target(operands)?,
+            },
+            "jumpif" => Instruction::JumpIf {
+                test: operands.register()?,
+                instruction: self.target(operands)?,
+            },
+            "storei" => Instruction::StoreI {
+                register: operands.register()?,
+                constant: operands.constant()?,
+            },
+            "copy" => Instruction::Copy {
+                dest: operands.register()?,
+                src: operands.register()?,
+            },
+            "subtract" => Instruction::Subtract {
+                dest: operands.register()?,
+                lhs: operands.register()?,
+                rhs: operands.register()?,
+            },
+            "subtracti" => Instruction::SubtractI {
+                register: operands.register()?,
+                constant: operands.constant()?,
+            },
+            "add" => Instruction::Add {
+                dest: operands.register()?,
+                lhs: operands.register()?,
+                rhs: operands.register()?,
+            },
+            "addi" => Instruction::AddI {
+                register: operands.register()?,
+                constant: operands.constant()?,
+            },
+            "shiftleft" => Instruction::ShiftLeft {
+                register: operands.register()?,
+                amount: operands.amount()?,
+            },
+            "shiftright" => Instruction::ShiftRight {
+                register: operands.register()?,
+                amount: operands.amount()?,
+            },
+            "shiftrighta" => Instruction::ShiftRightArithmetic {
+                register: operands.register()?,
+                amount: operands.amount()?,
+            },
+            "store" => Instruction::Store {
+                slot: operands.slot()?,
+                register: operands.register()?,
+            },
+            "load" => Instruction::Load {
+                register: operands.register()?,
+                slot: operands.slot()?,
+            },
+            "trap" => Instruction::Trap {
+                kind: operands.trap()?,
+            },
+            _ => {
+                return Err(Diagnostic::new(
+                    format!("Unknown instruction {:?}", mnemonic.text),
+                    mnemonic.span(),
+                ))
+            }
+        };
+        operands.finish()?;
+        Ok(instruction)
+    }
+
+    /// The label a jump goes to.
+    fn target(&mut self, operands: &mut Operands<'a>) -> Result<Label, Diagnostic> {
+        Ok(match operands.target()? {
+            Target::Index(index) => {
+                let label = self.labels.label();
+                self.labels.define(label, index as usize);
+                label
+            }
+            Target::Name(name) => {
+                let label = self.name(name);
+                self.places.entry(label).or_insert(name);
+                label
+            }
+        })
+    }
+
+    /// The label called `name`.
+    fn name(&mut self, name: Piece<'a>) -> Label {
+        *self
+            .names
+            .entry(name.text)
+            .or_insert_with(|| self.labels.label())
+    }
+
+    /// Points the jumps at the instructions their labels stand for.
+    fn link(mut self) -> Result<Vec<Instruction>, Vec<Diagnostic>> {
+        match self.labels.link(self.program) {
+            Ok(program) if self.diagnostics.is_empty() => return Ok(program),
+            Ok(_) => {}
+            Err(errors) => {
+                for error in errors {
+                    let (label, message) = match error {
+                        LinkError::Undefined { label } => (label, "is not defined"),
+                        LinkError::DefinedTwice { label } => (label, "is defined twice"),
+                        LinkError::OutOfRange { label, .. } => {
+                            (label, "is past the last instruction a jump can reach")
+                        }
+                        LinkError::TooManyLabels => {
+                            self.diagnostics
+                                .push(Diagnostic::new(error.to_string(), Span::new(0, 0)));
+                            continue;
+                        }
+                    };
+                    let place = self.places[&label];
+                    self.diagnostics.push(Diagnostic::new(
+                        format!("Label {} {message}", place.text),
+                        place.span(),
+                    ));
+                }
+            }
+        }
+        self.diagnostics
+            .sort_by_key(|diagnostic| diagnostic.span.offset);
+        Err(self.diagnostics)
+    }
+}
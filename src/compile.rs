@@ -1,275 +1,708 @@
+use crate::diagnostic::{Diagnostic, Span};
 use crate::execute::Instruction::{
-    Add, AddI, AndI, Copy, Jump, JumpIf, Negate, ShiftLeft, ShiftRight, StoreI, Subtract, SubtractI,
+    Add, AddI, AndI, Copy, Jump, JumpIf, Negate, ShiftLeft, ShiftRight, ShiftRightArithmetic,
+    StoreI, Subtract, SubtractI, Trap,
 };
-use crate::execute::{Instruction, Label, RegisterContent};
+use crate::execute::{Instruction, Label, Register, RegisterContent, TrapKind};
+use crate::link::{Labels, LinkError};
 use crate::parsing::Node;
+use crate::regalloc::{self, VirtualRegister};
+use crate::width::Width;
+
+pub const RESULT_REGISTER: Register = 3;
+/// Where `compile_divmod` leaves the remainder.
+pub const REMAINDER_REGISTER: Register = RESULT_REGISTER - 1;
+
+/// Which way a signed quotient that is not whole gets rounded, and so which sign the remainder
+/// takes. Unsigned division is the same for all of them.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Division {
+    /// Towards zero, the remainder has the sign of the dividend. C and Rust's `/` and `%`.
+    Truncated,
+    /// Towards negative infinity, the remainder has the sign of the divisor. Python's `//` and `%`.
+    Floored,
+    /// The remainder is never negative. Rust's `div_euclid` and `rem_euclid`.
+    Euclidean,
+}
+
+/// How the bits of an operand are read.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Signedness {
+    Unsigned,
+    /// Two's complement, the top bit of an operand counts negative.
+    Signed,
+}
+
+/// A program for a `Machine` and where in the input each of its instructions came from.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Compiled {
+    pub program: Vec<Instruction>,
+    /// The span of the node every instruction was emitted for, `None` for the moves of the
+    /// results into their registers at the end.
+    pub spans: Vec<Option<Span>>,
+}
 
-pub const RESULT_REGISTER: u8 = 3;
-pub const REMAINDER_REGISTER: u8 = 3;
-pub const ITERATION_REGISTER: u8 = 0;
-const MULTIPLIER_REGISTER: u8 = 1;
-const MULTIPLICAND_REGISTER: u8 = 2;
-const DIVISOR_REGISTER: u8 = 2;
-const QUOTIENT_REGISTER: u8 = 1;
-const TEST_REGISTER: u8 = 4;
 pub struct Compiler {
-    instructions: Vec<Instruction>,
+    /// Emitted code, jumping to labels from `labels` until it is linked.
+    instructions: Vec<Instruction<VirtualRegister>>,
+    /// The span of the node each emitted instruction belongs to.
+    spans: Vec<Span>,
+    labels: Labels,
     ast: Vec<Node>,
+    width: Width,
+    signedness: Signedness,
+    division: Division,
+    next_register: VirtualRegister,
 }
 
 impl Compiler {
-    pub fn new(ast: Vec<Node>) -> Self {
+    pub fn new(ast: Vec<Node>, width: Width, signedness: Signedness, division: Division) -> Self {
         Self {
             instructions: Vec::new(),
+            spans: Vec::new(),
+            labels: Labels::default(),
             ast,
+            width,
+            signedness,
+            division,
+            next_register: 0,
         }
     }
 
-    pub fn compile(mut self) -> Result<Vec<Instruction>, String> {
-        for node in &self.ast {
-            Self::compile_node(&mut self.instructions, node)?;
+    /// Compiles for a `Machine` with `registers` registers, leaving the answer in `RESULT_REGISTER`.
+    pub fn compile(mut self, registers: u8) -> Result<Compiled, Diagnostic> {
+        let ast = std::mem::take(&mut self.ast);
+        let mut result = None;
+        for node in &ast {
+            result = Some(self.compile_node(node)?);
         }
-        Ok(self.instructions)
+        let results = result.map(|result| (result, RESULT_REGISTER));
+        self.finish(&ast, results.as_slice(), registers)
+    }
+
+    /// Compiles a single division for a `Machine` with `registers` registers, leaving the quotient
+    /// in `RESULT_REGISTER` and the remainder in `REMAINDER_REGISTER`.
+    pub fn compile_divmod(mut self, registers: u8) -> Result<Compiled, Diagnostic> {
+        let ast = std::mem::take(&mut self.ast);
+        let (quotient, remainder) = match ast.as_slice() {
+            [node @ (Node::DivN { lhs, rhs } | Node::ModN { lhs, rhs })] => {
+                let division = self.division(lhs, rhs)?;
+                self.spans.resize(self.instructions.len(), node.span());
+                division
+            }
+            [node, ..] => return Err(Diagnostic::new("Expected a division", node.span())),
+            [] => return Err(Diagnostic::new("Expected a division", Span::new(0, 0))),
+        };
+        let results = [(quotient, RESULT_REGISTER), (remainder, REMAINDER_REGISTER)];
+        self.finish(&ast, &results, registers)
+    }
+
+    /// Links the emitted code and maps its registers onto the machine's, `results` ending up in
+    /// the registers paired with them.
+    fn finish(
+        self,
+        ast: &[Node],
+        results: &[(VirtualRegister, Register)],
+        registers: u8,
+    ) -> Result<Compiled, Diagnostic> {
+        let span = match (ast.first(), ast.last()) {
+            (Some(first), Some(last)) => first.span().to(last.span()),
+            _ => Span::new(0, 0),
+        };
+        let program = self
+            .labels
+            .link(self.instructions)
+            .map_err(|errors| match errors[0] {
+                LinkError::TooManyLabels | LinkError::OutOfRange { .. } => {
+                    Diagnostic::new("Program too long", span)
+                }
+                _ => Diagnostic::new(format!("Could not link: {}", errors[0]), span),
+            })?;
+        let (program, origins) = regalloc::allocate(program, results, registers)
+            .ok_or_else(|| Diagnostic::new("Program too long", span))?;
+        let spans = origins
+            .into_iter()
+            .map(|origin| self.spans.get(origin).copied())
+            .collect();
+        Ok(Compiled { program, spans })
+    }
+
+    fn register(&mut self) -> VirtualRegister {
+        self.next_register += 1;
+        self.next_register - 1
+    }
+
+    /// Emits code for `node`, returning the register holding its value. That register is the
+    /// caller's to overwrite.
+    fn compile_node(&mut self, node: &Node) -> Result<VirtualRegister, Diagnostic> {
+        let result = self.emit_node(node)?;
+        // Whatever the operands did not emit is this node's own code
+        self.spans.resize(self.instructions.len(), node.span());
+        Ok(result)
     }
 
-    fn compile_node(instructions: &mut Vec<Instruction>, node: &Node) -> Result<(), String> {
+    fn emit_node(&mut self, node: &Node) -> Result<VirtualRegister, Diagnostic> {
         match node {
-            Node::NumberN(x) => {
-                instructions.push(StoreI {
+            Node::NumberN(x, _) => {
+                let result = self.register();
+                self.instructions.push(StoreI {
                     constant: *x,
-                    register: RESULT_REGISTER,
+                    register: result,
                 });
-                Ok(())
+                Ok(result)
             }
             Node::MulN { lhs, rhs } => {
-                //lhs can be another type
-                Self::compile_node(instructions, lhs.as_ref())?;
-                instructions.push(Copy {
-                    src: RESULT_REGISTER,
-                    dest: MULTIPLICAND_REGISTER,
-                });
+                let multiplicand = self.compile_node(lhs)?;
+                let multiplier = self.compile_node(rhs)?;
+                if self.signedness == Signedness::Signed {
+                    return Ok(self.booth(multiplicand, multiplier));
+                }
+                let result = self.register();
+                let iteration = self.register();
+                let test = self.register();
+                let (next_bit, skip_add) = (self.labels.label(), self.labels.label());
+                let instructions = &mut self.instructions;
+
                 //Truncate input
                 instructions.push(AndI {
-                    register: MULTIPLICAND_REGISTER,
-                    constant: 0xF,
+                    register: multiplicand,
+                    constant: self.width.mask(),
                 });
-
-                //rhs is always a number
-                Self::compile_node(instructions, rhs.as_ref())?;
-                instructions.push(Copy {
-                    src: RESULT_REGISTER,
-                    dest: MULTIPLIER_REGISTER,
+                instructions.push(AndI {
+                    register: multiplier,
+                    constant: self.width.mask(),
                 });
 
                 // Zero-out answer register
-                instructions.push(AndI {
-                    register: RESULT_REGISTER,
+                instructions.push(StoreI {
                     constant: 0,
+                    register: result,
                 });
 
                 instructions.push(StoreI {
-                    constant: 4,
-                    register: ITERATION_REGISTER,
+                    constant: self.width.bits() as RegisterContent,
+                    register: iteration,
                 });
 
-                let jump_point = instructions.len() as u16;
+                self.labels.define(next_bit, instructions.len());
 
                 //Step 1
                 instructions.push(Copy {
-                    src: MULTIPLIER_REGISTER,
-                    dest: TEST_REGISTER,
-                });
-                instructions.push(Negate {
-                    register: TEST_REGISTER,
+                    src: multiplier,
+                    dest: test,
                 });
+                instructions.push(Negate { register: test });
                 instructions.push(AndI {
-                    register: TEST_REGISTER,
+                    register: test,
                     constant: 1,
                 });
-                let forward = instructions.len() + 2;
                 instructions.push(JumpIf {
-                    instruction: forward as Label,
-                    test: TEST_REGISTER,
+                    instruction: skip_add,
+                    test,
                 });
                 instructions.push(Add {
-                    lhs: MULTIPLICAND_REGISTER,
-                    rhs: RESULT_REGISTER,
-                    dest: RESULT_REGISTER,
+                    lhs: multiplicand,
+                    rhs: result,
+                    dest: result,
                 });
+                self.labels.define(skip_add, instructions.len());
 
                 //step 2
                 instructions.push(ShiftLeft {
-                    register: MULTIPLICAND_REGISTER,
+                    register: multiplicand,
                     amount: 1,
                 });
 
                 //step 3
                 instructions.push(ShiftRight {
-                    register: MULTIPLIER_REGISTER,
+                    register: multiplier,
                     amount: 1,
                 });
 
                 //Iterate
                 instructions.push(SubtractI {
-                    register: ITERATION_REGISTER,
+                    register: iteration,
                     constant: 1,
                 });
 
                 instructions.push(JumpIf {
-                    instruction: jump_point,
-                    test: ITERATION_REGISTER,
+                    instruction: next_bit,
+                    test: iteration,
                 });
 
-                Ok(())
+                Ok(result)
             }
-            Node::DivN { lhs, rhs } => {
-                //lhs can be another type
-                Self::compile_node(instructions, lhs.as_ref())?;
-                instructions.push(Copy {
-                    src: RESULT_REGISTER,
-                    dest: TEST_REGISTER,
-                });
-                //Truncate input.
-                instructions.push(AndI {
-                    register: TEST_REGISTER,
-                    constant: 0xF,
-                });
-
-                //rhs is always a number
-                Self::compile_node(instructions, rhs.as_ref())?;
-                instructions.push(Copy {
-                    src: RESULT_REGISTER,
-                    dest: DIVISOR_REGISTER, //This copy fails?
-                });
-
-                instructions.push(Copy {
-                    src: TEST_REGISTER,
-                    dest: RESULT_REGISTER,
-                });
-
-                //If we're dividing zero, return zero. Dividing by zero is fine, or add a TRAP?
-                instructions.push(Copy {
-                    src: RESULT_REGISTER,
-                    dest: TEST_REGISTER,
-                });
-                instructions.push(AddI {
-                    register: TEST_REGISTER,
-                    constant: 15,
-                });
-                instructions.push(Negate {
-                    register: TEST_REGISTER,
-                });
-                instructions.push(ShiftRight {
-                    register: TEST_REGISTER,
-                    amount: 4,
-                });
-                instructions.push(AndI {
-                    register: TEST_REGISTER,
-                    constant: 1,
-                });
-                let zero_label = instructions.len() + 16;
-                instructions.push(JumpIf {
-                    instruction: zero_label as Label,
-                    test: TEST_REGISTER,
-                });
-
-                //Resume algorithm
-                instructions.push(ShiftLeft {
-                    register: DIVISOR_REGISTER,
-                    amount: 4,
-                });
-
-                // We want the remainder in the answer register anyway
-
-                //Zero-out quotient register
-                instructions.push(StoreI {
-                    constant: 0,
-                    register: QUOTIENT_REGISTER,
-                });
-
-                instructions.push(StoreI {
-                    constant: 5,
-                    register: ITERATION_REGISTER,
-                });
-
-                let jump_point = instructions.len() as u16;
-
-                //step 1
-                instructions.push(Subtract {
-                    lhs: REMAINDER_REGISTER,
-                    rhs: DIVISOR_REGISTER,
-                    dest: REMAINDER_REGISTER,
-                });
-
-                //step 2
-                instructions.push(Copy {
-                    src: REMAINDER_REGISTER,
-                    dest: TEST_REGISTER,
-                });
+            Node::DivN { lhs, rhs } => Ok(self.division(lhs, rhs)?.0),
+            Node::ModN { lhs, rhs } => Ok(self.division(lhs, rhs)?.1),
+            Node::AddN { lhs, rhs } | Node::SubN { lhs, rhs } => {
+                let lhs = self.compile_node(lhs)?;
+                let rhs = self.compile_node(rhs)?;
+                match self.signedness {
+                    Signedness::Unsigned => {
+                        //Truncate input
+                        for register in [lhs, rhs] {
+                            self.instructions.push(AndI {
+                                register,
+                                constant: self.width.mask(),
+                            });
+                        }
+                    }
+                    Signedness::Signed => {
+                        self.sign_extend(lhs);
+                        self.sign_extend(rhs);
+                    }
+                }
+                let instructions = &mut self.instructions;
+
+                if let Node::AddN { .. } = node {
+                    instructions.push(Add {
+                        lhs,
+                        rhs,
+                        dest: lhs,
+                    });
+                } else {
+                    instructions.push(Subtract {
+                        lhs,
+                        rhs,
+                        dest: lhs,
+                    });
+                }
+
+                Ok(lhs)
+            }
+            Node::Temp(_, span) => Err(Diagnostic::new("Bad parsing!", *span)),
+        }
+    }
 
-                instructions.push(AndI {
-                    register: TEST_REGISTER,
-                    constant: RegisterContent::MIN,
-                });
+    /// Emits `lhs / rhs`, returning the registers holding the quotient and the remainder.
+    fn division(
+        &mut self,
+        lhs: &Node,
+        rhs: &Node,
+    ) -> Result<(VirtualRegister, VirtualRegister), Diagnostic> {
+        let dividend = self.compile_node(lhs)?;
+        let divisor = self.compile_node(rhs)?;
+        Ok(match self.signedness {
+            Signedness::Unsigned => self.divide(dividend, divisor),
+            Signedness::Signed => self.divide_signed(dividend, divisor),
+        })
+    }
 
-                let label1 = (instructions.len() + 4) as u16;
-                instructions.push(JumpIf {
-                    instruction: label1,
-                    test: TEST_REGISTER,
-                });
-                //branch for rem >= 0
-                instructions.push(ShiftLeft {
-                    register: QUOTIENT_REGISTER,
-                    amount: 1,
-                });
-                instructions.push(AddI {
-                    //Could use SubtractI with negative number but whatevs.
-                    register: QUOTIENT_REGISTER,
-                    constant: 1,
-                });
+    /// Restoring division of the unsigned operands, returning the registers holding the quotient
+    /// and the remainder. Those are `remainder` and the caller's to overwrite.
+    fn divide(
+        &mut self,
+        remainder: VirtualRegister,
+        divisor: VirtualRegister,
+    ) -> (VirtualRegister, VirtualRegister) {
+        let quotient = self.register();
+        let iteration = self.register();
+        let test = self.register();
+        let labels = &mut self.labels;
+        let [nonzero, zero_dividend, next_bit, negative, shift] = [(); 5].map(|_| labels.label());
+        let instructions = &mut self.instructions;
+
+        //Truncate input.
+        instructions.push(AndI {
+            register: remainder,
+            constant: self.width.mask(),
+        });
+        instructions.push(AndI {
+            register: divisor,
+            constant: self.width.mask(),
+        });
+
+        //Dividing by zero stops the machine
+        instructions.push(JumpIf {
+            instruction: nonzero,
+            test: divisor,
+        });
+        instructions.push(Trap {
+            kind: TrapKind::DivisionByZero,
+        });
+        labels.define(nonzero, instructions.len());
+
+        //Zero-out quotient register, the zero dividend path below returns it as well
+        instructions.push(StoreI {
+            constant: 0,
+            register: quotient,
+        });
+
+        //If we're dividing zero, return zero.
+        instructions.push(Copy {
+            src: remainder,
+            dest: test,
+        });
+        instructions.push(AddI {
+            register: test,
+            constant: self.width.mask(),
+        });
+        instructions.push(Negate { register: test });
+        instructions.push(ShiftRight {
+            register: test,
+            amount: self.width.bits(),
+        });
+        instructions.push(AndI {
+            register: test,
+            constant: 1,
+        });
+        instructions.push(JumpIf {
+            instruction: zero_dividend,
+            test,
+        });
+
+        //Resume algorithm
+        instructions.push(ShiftLeft {
+            register: divisor,
+            amount: self.width.bits(),
+        });
+
+        instructions.push(StoreI {
+            constant: self.width.bits() as RegisterContent + 1,
+            register: iteration,
+        });
+
+        labels.define(next_bit, instructions.len());
+
+        //step 1
+        instructions.push(Subtract {
+            lhs: remainder,
+            rhs: divisor,
+            dest: remainder,
+        });
+
+        //step 2
+        instructions.push(Copy {
+            src: remainder,
+            dest: test,
+        });
+
+        instructions.push(AndI {
+            register: test,
+            constant: RegisterContent::MIN,
+        });
+
+        instructions.push(JumpIf {
+            instruction: negative,
+            test,
+        });
+        //branch for rem >= 0
+        instructions.push(ShiftLeft {
+            register: quotient,
+            amount: 1,
+        });
+        instructions.push(AddI {
+            //Could use SubtractI with negative number but whatevs.
+            register: quotient,
+            constant: 1,
+        });
+
+        instructions.push(Jump { instruction: shift }); //Jump to step 3
+
+        //branch for rem < 0
+        labels.define(negative, instructions.len());
+        instructions.push(Add {
+            lhs: remainder,
+            rhs: divisor,
+            dest: remainder,
+        });
+        instructions.push(ShiftLeft {
+            register: quotient,
+            amount: 1,
+        });
+
+        //step 3
+        labels.define(shift, instructions.len());
+        instructions.push(ShiftRight {
+            register: divisor,
+            amount: 1,
+        });
+
+        //Iterate
+        instructions.push(SubtractI {
+            register: iteration,
+            constant: 1,
+        });
+
+        instructions.push(JumpIf {
+            instruction: next_bit,
+            test: iteration,
+        });
+        labels.define(zero_dividend, instructions.len());
+
+        (quotient, remainder)
+    }
 
-                let label2 = (instructions.len() + 3) as u16;
-                instructions.push(Jump {
-                    instruction: label2,
-                }); //Jump to step 3
+    /// Divides the magnitudes and fixes up the signs afterwards, rounding the quotient the way
+    /// `self.division` says.
+    fn divide_signed(
+        &mut self,
+        dividend: VirtualRegister,
+        divisor: VirtualRegister,
+    ) -> (VirtualRegister, VirtualRegister) {
+        let dividend_negative = self.register();
+        let divisor_negative = self.register();
+        let original_divisor = self.register();
+        let test = self.register();
+
+        for (register, negative) in [(dividend, dividend_negative), (divisor, divisor_negative)] {
+            self.sign_extend(register);
+            self.instructions.push(Copy {
+                src: register,
+                dest: negative,
+            });
+            self.instructions.push(ShiftRight {
+                register: negative,
+                amount: RegisterContent::BITS as u8 - 1,
+            });
+        }
+        self.instructions.push(Copy {
+            src: divisor,
+            dest: original_divisor,
+        });
+        self.negate_if(dividend, dividend_negative, test);
+        self.negate_if(divisor, divisor_negative, test);
+
+        let (quotient, remainder) = self.divide(dividend, divisor);
+
+        //Truncated, the quotient is negative when the signs differ
+        let quotient_negative = divisor_negative;
+        self.instructions.push(Add {
+            lhs: quotient_negative,
+            rhs: dividend_negative,
+            dest: quotient_negative,
+        });
+        self.instructions.push(AndI {
+            register: quotient_negative,
+            constant: 1,
+        });
+        self.negate_if(quotient, quotient_negative, test);
+        self.negate_if(remainder, dividend_negative, test);
+
+        let mut skips = Vec::new();
+        if self.division != Division::Truncated {
+            //Floored, a nonzero remainder takes the sign of the divisor
+            skips.push(self.skip_if_zero(remainder));
+            self.instructions.push(Copy {
+                src: original_divisor,
+                dest: test,
+            });
+            self.instructions.push(ShiftRight {
+                register: test,
+                amount: RegisterContent::BITS as u8 - 1,
+            });
+            self.instructions.push(Subtract {
+                lhs: test,
+                rhs: dividend_negative,
+                dest: test,
+            });
+            let same_signs = self.skip_if_zero(test);
+            self.instructions.push(SubtractI {
+                register: quotient,
+                constant: 1,
+            });
+            self.instructions.push(Add {
+                lhs: remainder,
+                rhs: original_divisor,
+                dest: remainder,
+            });
+            self.land(same_signs);
+        }
+        if self.division == Division::Euclidean {
+            //Euclidean, a remainder left negative by a negative divisor goes positive
+            self.instructions.push(Copy {
+                src: remainder,
+                dest: test,
+            });
+            self.instructions.push(ShiftRight {
+                register: test,
+                amount: RegisterContent::BITS as u8 - 1,
+            });
+            skips.push(self.skip_if_zero(test));
+            self.instructions.push(AddI {
+                register: quotient,
+                constant: 1,
+            });
+            self.instructions.push(Subtract {
+                lhs: remainder,
+                rhs: original_divisor,
+                dest: remainder,
+            });
+        }
+        for skip in skips {
+            self.land(skip);
+        }
 
-                //branch for rem < 0
-                instructions.push(Add {
-                    lhs: REMAINDER_REGISTER,
-                    rhs: DIVISOR_REGISTER,
-                    dest: REMAINDER_REGISTER,
-                });
-                instructions.push(ShiftLeft {
-                    register: QUOTIENT_REGISTER,
-                    amount: 1,
-                });
+        (quotient, remainder)
+    }
 
-                //step 3
-                instructions.push(ShiftRight {
-                    register: DIVISOR_REGISTER,
-                    amount: 1,
-                });
+    /// Copies the sign bit of an operand into every bit above it.
+    fn sign_extend(&mut self, register: VirtualRegister) {
+        let amount = RegisterContent::BITS as u8 - self.width.bits();
+        self.instructions.push(ShiftLeft { register, amount });
+        self.instructions
+            .push(ShiftRightArithmetic { register, amount });
+    }
 
-                //Iterate
-                instructions.push(SubtractI {
-                    register: ITERATION_REGISTER,
-                    constant: 1,
-                });
+    /// Two's complement negation of `register` when `condition` holds 1, leaves it alone for 0.
+    fn negate_if(
+        &mut self,
+        register: VirtualRegister,
+        condition: VirtualRegister,
+        test: VirtualRegister,
+    ) {
+        let skip = self.labels.label();
+        let instructions = &mut self.instructions;
+        instructions.push(Copy {
+            src: condition,
+            dest: test,
+        });
+        instructions.push(SubtractI {
+            register: test,
+            constant: 1,
+        });
+        instructions.push(JumpIf {
+            instruction: skip,
+            test,
+        });
+        instructions.push(Negate { register });
+        instructions.push(AddI {
+            register,
+            constant: 1,
+        });
+        self.labels.define(skip, instructions.len());
+    }
 
-                instructions.push(JumpIf {
-                    instruction: jump_point,
-                    test: ITERATION_REGISTER,
-                });
+    /// Jumps over the code emitted next when `test` is zero, until `land` is called with the
+    /// returned label.
+    fn skip_if_zero(&mut self, test: VirtualRegister) -> Label {
+        let (nonzero, skip) = (self.labels.label(), self.labels.label());
+        self.instructions.push(JumpIf {
+            instruction: nonzero,
+            test,
+        });
+        self.instructions.push(Jump { instruction: skip });
+        self.land(nonzero);
+        skip
+    }
 
-                //Forget remainder
-                instructions.push(Copy {
-                    src: QUOTIENT_REGISTER,
-                    dest: RESULT_REGISTER,
-                });
+    /// Points jumps to `label` at the next instruction.
+    fn land(&mut self, label: Label) {
+        self.labels.define(label, self.instructions.len());
+    }
 
-                Ok(())
-            }
-            Node::Temp(_) => Err("Bad parsing!".to_string()),
-        }
+    /// Booth's algorithm for two's complement operands. The product register holds the
+    /// accumulator above the multiplier and one extra bit below it, all shifted right together.
+    fn booth(
+        &mut self,
+        multiplicand: VirtualRegister,
+        multiplier: VirtualRegister,
+    ) -> VirtualRegister {
+        let product = multiplier;
+        let iteration = self.register();
+        let test = self.register();
+        let labels = &mut self.labels;
+        let [next_bit, skip_add, skip_subtract] = [(); 3].map(|_| labels.label());
+        let instructions = &mut self.instructions;
+        let bits = self.width.bits();
+
+        //Sign-extend the multiplicand and line it up with the accumulator
+        instructions.push(ShiftLeft {
+            register: multiplicand,
+            amount: RegisterContent::BITS as u8 - bits,
+        });
+        instructions.push(ShiftRightArithmetic {
+            register: multiplicand,
+            amount: RegisterContent::BITS as u8 - 1 - 2 * bits,
+        });
+
+        //Truncate the multiplier and make room for the extra bit, the accumulator starts at zero
+        instructions.push(AndI {
+            register: product,
+            constant: self.width.mask(),
+        });
+        instructions.push(ShiftLeft {
+            register: product,
+            amount: 1,
+        });
+
+        instructions.push(StoreI {
+            constant: bits as RegisterContent,
+            register: iteration,
+        });
+
+        labels.define(next_bit, instructions.len());
+
+        //Step 1, bits 01 add the multiplicand
+        instructions.push(Copy {
+            src: product,
+            dest: test,
+        });
+        instructions.push(AndI {
+            register: test,
+            constant: 0b11,
+        });
+        instructions.push(SubtractI {
+            register: test,
+            constant: 0b01,
+        });
+        instructions.push(JumpIf {
+            instruction: skip_add,
+            test,
+        });
+        instructions.push(Add {
+            lhs: product,
+            rhs: multiplicand,
+            dest: product,
+        });
+        labels.define(skip_add, instructions.len());
+
+        //bits 10 subtract it, adding left the low bits alone
+        instructions.push(Copy {
+            src: product,
+            dest: test,
+        });
+        instructions.push(AndI {
+            register: test,
+            constant: 0b11,
+        });
+        instructions.push(SubtractI {
+            register: test,
+            constant: 0b10,
+        });
+        instructions.push(JumpIf {
+            instruction: skip_subtract,
+            test,
+        });
+        instructions.push(Subtract {
+            lhs: product,
+            rhs: multiplicand,
+            dest: product,
+        });
+        labels.define(skip_subtract, instructions.len());
+
+        //Step 2
+        instructions.push(ShiftRightArithmetic {
+            register: product,
+            amount: 1,
+        });
+
+        //Iterate
+        instructions.push(SubtractI {
+            register: iteration,
+            constant: 1,
+        });
+        instructions.push(JumpIf {
+            instruction: next_bit,
+            test: iteration,
+        });
+
+        //Drop the extra bit
+        instructions.push(ShiftRightArithmetic {
+            register: product,
+            amount: 1,
+        });
+
+        product
     }
 }
@@ -0,0 +1,229 @@
+use crate::asm::number;
+use crate::execute::{
+    Instruction, Label, Machine, MachineError, Register, RegisterContent, Status, DEFAULT_FUEL,
+};
+use crate::trace::Tracer;
+use std::collections::BTreeSet;
+use std::io::{self, BufRead, Write};
+
+/// Instructions `list` shows before and after the pc.
+const LIST_CONTEXT: Label = 3;
+
+const HELP: &str = "\
+step [n]       run n instructions, one if left out (s)
+continue       run until a breakpoint or the end (c)
+back [n]       undo n instructions, one if left out
+break <i>      stop before instruction i (b)
+delete <i>     remove the breakpoint on instruction i (d)
+registers      print every register (r)
+set r<n> <v>   put v in register n
+history r<n>   what register n held after every instruction so far
+list           show the instructions around the pc (l)
+quit           stop debugging (q)";
+
+#[derive(Debug, Eq, PartialEq)]
+enum Command {
+    Step(u64),
+    Continue,
+    Back(u64),
+    Break(Label),
+    Delete(Label),
+    Registers,
+    Set(Register, RegisterContent),
+    History(Register),
+    List,
+    Help,
+    Quit,
+}
+
+impl Command {
+    fn parse(line: &str) -> Result<Self, String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let label = |word: Option<&&str>| {
+            word.and_then(|word| word.parse().ok())
+                .ok_or("Expected an instruction index")
+        };
+        let register = |word: &str| {
+            word.strip_prefix('r')
+                .and_then(|register| register.parse().ok())
+                .ok_or("Expected a register like r3")
+        };
+        Ok(match words.as_slice() {
+            ["step" | "s"] => Command::Step(1),
+            ["step" | "s", count] => {
+                Command::Step(count.parse().map_err(|_| "Expected a number of steps")?)
+            }
+            ["continue" | "c"] => Command::Continue,
+            ["back"] => Command::Back(1),
+            ["back", count] => {
+                Command::Back(count.parse().map_err(|_| "Expected a number of steps")?)
+            }
+            ["break" | "b", rest @ ..] if rest.len() <= 1 => Command::Break(label(rest.first())?),
+            ["delete" | "d", rest @ ..] if rest.len() <= 1 => Command::Delete(label(rest.first())?),
+            ["registers" | "r"] => Command::Registers,
+            ["set", which, value] => {
+                Command::Set(register(which)?, number(value).ok_or("Expected a value")?)
+            }
+            ["history", which] => Command::History(register(which)?),
+            ["list" | "l"] => Command::List,
+            ["help" | "h"] => Command::Help,
+            ["quit" | "q"] => Command::Quit,
+            _ => return Err(format!("Unknown command {line:?}, try help")),
+        })
+    }
+}
+
+/// Runs a program on a `Machine` a command at a time.
+pub struct Debugger<T> {
+    machine: Machine<T>,
+    breakpoints: BTreeSet<Label>,
+}
+
+impl<T: Tracer> Debugger<T> {
+    pub fn new(machine: Machine<T>, program: Vec<Instruction>) -> Self {
+        let mut machine = machine.record();
+        machine.load(program);
+        Self {
+            machine,
+            breakpoints: BTreeSet::new(),
+        }
+    }
+
+    /// Reads commands from `input` until it ends or says quit, answering on `output`.
+    pub fn repl(&mut self, input: impl BufRead, mut output: impl Write) -> io::Result<()> {
+        writeln!(
+            output,
+            "{} instructions loaded, help lists the commands",
+            self.machine.program().len()
+        )?;
+        self.list(&mut output)?;
+        write!(output, "(debug) ")?;
+        output.flush()?;
+        for line in input.lines() {
+            let line = line?;
+            if !line.trim().is_empty() {
+                match Command::parse(&line) {
+                    Ok(Command::Quit) => return Ok(()),
+                    Ok(command) => self.execute(command, &mut output)?,
+                    Err(message) => writeln!(output, "{message}")?,
+                }
+            }
+            write!(output, "(debug) ")?;
+            output.flush()?;
+        }
+        writeln!(output)
+    }
+
+    fn execute(&mut self, command: Command, output: &mut impl Write) -> io::Result<()> {
+        match command {
+            Command::Step(count) => {
+                self.machine.add_fuel(count);
+                for _ in 0..count {
+                    if !self.step(output)? {
+                        return Ok(());
+                    }
+                }
+                self.list(output)
+            }
+            Command::Continue => {
+                self.machine.add_fuel(DEFAULT_FUEL);
+                while self.step(output)? {
+                    let pc = self.machine.pc();
+                    if self.breakpoints.contains(&pc) {
+                        writeln!(output, "Breakpoint at {pc}")?;
+                        return self.list(output);
+                    }
+                }
+                Ok(())
+            }
+            Command::Back(count) => {
+                let steps = self.machine.steps();
+                match steps.checked_sub(count) {
+                    Some(step) if self.machine.rewind(step) => {
+                        writeln!(output, "Back to {step} steps")?;
+                        self.list(output)
+                    }
+                    _ => writeln!(output, "Only {steps} steps to go back"),
+                }
+            }
+            Command::Break(label) if label as usize >= self.machine.program().len() => {
+                writeln!(output, "There is no instruction {label}")
+            }
+            Command::Break(label) => {
+                self.breakpoints.insert(label);
+                writeln!(output, "Breakpoint at {label}")
+            }
+            Command::Delete(label) => {
+                if self.breakpoints.remove(&label) {
+                    writeln!(output, "Deleted the breakpoint at {label}")
+                } else {
+                    writeln!(output, "There is no breakpoint at {label}")
+                }
+            }
+            Command::Registers => {
+                writeln!(output, "pc = {}", self.machine.pc())?;
+                for (register, value) in self.machine.registers().iter().enumerate() {
+                    writeln!(output, "r{register} = {value} ({value:08b})")?;
+                }
+                Ok(())
+            }
+            Command::Set(register, value) => match self.machine.set_register(register, value) {
+                Ok(()) => writeln!(output, "r{register} = {value} ({value:08b})"),
+                Err(_) => writeln!(output, "There is no register r{register}"),
+            },
+            Command::History(register) => match self.machine.register_history(register) {
+                Some(values) => {
+                    for (step, value) in values.iter().enumerate() {
+                        writeln!(output, "{step:>6}  {value} ({value:08b})")?;
+                    }
+                    Ok(())
+                }
+                None => writeln!(output, "There is no register r{register}"),
+            },
+            Command::List => self.list(output),
+            Command::Help => writeln!(output, "{HELP}"),
+            Command::Quit => Ok(()),
+        }
+    }
+
+    /// Runs one instruction, reporting on `output` and returning false if the machine stopped.
+    fn step(&mut self, output: &mut impl Write) -> io::Result<bool> {
+        match self.machine.step() {
+            Ok(Status::Running) => Ok(true),
+            Ok(Status::Halted(outcome)) => {
+                writeln!(output, "Halted after {} steps", outcome.steps)?;
+                Ok(false)
+            }
+            Err(MachineError::StepLimitExceeded { limit }) => {
+                writeln!(output, "Paused after {limit} instructions")?;
+                Ok(false)
+            }
+            Err(error) => {
+                writeln!(output, "{error}")?;
+                Ok(false)
+            }
+        }
+    }
+
+    /// The instructions around the pc, with breakpoints marked.
+    fn list(&self, output: &mut impl Write) -> io::Result<()> {
+        let pc = self.machine.pc();
+        let program = self.machine.program();
+        let start = pc.saturating_sub(LIST_CONTEXT) as usize;
+        let end = (pc as usize + LIST_CONTEXT as usize + 1).min(program.len());
+        for (index, instruction) in program.iter().enumerate().take(end).skip(start) {
+            let label = index as Label;
+            let marker = if label == pc { "=>" } else { "  " };
+            let breakpoint = if self.breakpoints.contains(&label) {
+                '*'
+            } else {
+                ' '
+            };
+            writeln!(output, "{marker}{breakpoint}{index:>4}  {instruction}")?;
+        }
+        if pc as usize >= program.len() {
+            writeln!(output, "=> {pc:>4}  end")?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,67 @@
+/// Byte range of the input a token or node came from.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Span {
+    pub offset: usize,
+    pub len: usize,
+}
+
+impl Span {
+    pub fn new(offset: usize, len: usize) -> Self {
+        Self { offset, len }
+    }
+
+    /// Smallest span covering both `self` and `other`.
+    pub fn to(self, other: Span) -> Span {
+        let start = self.offset.min(other.offset);
+        let end = (self.offset + self.len).max(other.offset + other.len);
+        Span::new(start, end - start)
+    }
+}
+
+/// An error pointing at a span of the original input.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Diagnostic {
+    pub message: String,
+    pub span: Span,
+}
+
+impl Diagnostic {
+    pub fn new(message: impl Into<String>, span: Span) -> Self {
+        Self {
+            message: message.into(),
+            span,
+        }
+    }
+
+    /// The message, followed by the line of input with a caret under the offending span. When the
+    /// input has more than one line the message starts with the line and column.
+    pub fn render(&self, source: &str) -> String {
+        let before = source.get(..self.span.offset).unwrap_or(source);
+        let start = before.rfind('\n').map_or(0, |newline| newline + 1);
+        let line = source[start..].lines().next().unwrap_or("");
+        let before = &before[start..];
+        let marked = source
+            .get(self.span.offset..self.span.offset + self.span.len)
+            .unwrap_or("");
+        let marked = marked.lines().next().unwrap_or("");
+        let location = if source.contains('\n') {
+            let line_number = source[..start].matches('\n').count() + 1;
+            format!("{line_number}:{}: ", before.chars().count() + 1)
+        } else {
+            String::new()
+        };
+        format!(
+            "{location}{}\n  {}\n  {}{}",
+            self.message,
+            line,
+            " ".repeat(before.chars().count()),
+            "^".repeat(marked.chars().count().max(1))
+        )
+    }
+}
+
+impl From<Diagnostic> for Vec<Diagnostic> {
+    fn from(diagnostic: Diagnostic) -> Self {
+        vec![diagnostic]
+    }
+}
@@ -0,0 +1,223 @@
+//! Fixed-width binary encoding of instructions.
+//!
+//! Every instruction takes `INSTRUCTION_BYTES` bytes, an opcode, three single byte operands `a`,
+//! `b` and `c`, and a little endian 32 bit immediate:
+//!
+//! | opcode | instruction   | a        | b      | c   | immediate   |
+//! |--------|---------------|----------|--------|-----|-------------|
+//! | 0      | `negate`      | register |        |     |             |
+//! | 1      | `andi`        | register |        |     | constant    |
+//! | 2      | `jump`        |          |        |     | instruction |
+//! | 3      | `jumpif`      | test     |        |     | instruction |
+//! | 4      | `storei`      | register |        |     | constant    |
+//! | 5      | `copy`        | dest     | src    |     |             |
+//! | 6      | `subtract`    | dest     | lhs    | rhs |             |
+//! | 7      | `subtracti`   | register |        |     | constant    |
+//! | 8      | `add`         | dest     | lhs    | rhs |             |
+//! | 9      | `addi`        | register |        |     | constant    |
+//! | 10     | `shiftleft`   | register | amount |     |             |
+//! | 11     | `shiftright`  | register | amount |     |             |
+//! | 12     | `shiftrighta` | register | amount |     |             |
+//! | 13     | `store`       | register |        |     | slot        |
+//! | 14     | `load`        | register |        |     | slot        |
+//! | 15     | `trap`        | kind     |        |     |             |
+//!
+//! Unused operands are zero. The only kind of trap is 0, division by zero. A raw image is the
+//! encoded instructions one after the other, with nothing before or after them.
+
+use crate::execute::{Instruction, Label, RegisterContent, TrapKind};
+use std::fmt::{Display, Formatter};
+
+/// Bytes every encoded instruction takes.
+pub const INSTRUCTION_BYTES: usize = 8;
+/// Most instructions in an image, one for every `Label`.
+const MAX_INSTRUCTIONS: usize = Label::MAX as usize + 1;
+
+/// Why bytes are not an instruction.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum DecodeError {
+    UnknownOpcode(u8),
+    UnknownTrap(u8),
+    /// An operand the opcode does not use is not zero, or a jump target or memory slot does not
+    /// fit in 16 bits.
+    InvalidOperand,
+}
+
+impl Display for DecodeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecodeError::UnknownOpcode(opcode) => write!(f, "Unknown opcode {opcode}"),
+            DecodeError::UnknownTrap(kind) => write!(f, "Unknown kind of trap {kind}"),
+            DecodeError::InvalidOperand => write!(f, "Invalid operand"),
+        }
+    }
+}
+
+/// Why bytes are not a program.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum ImageError {
+    /// The image ends partway through an instruction.
+    Truncated {
+        len: usize,
+    },
+    /// More instructions than a `Label` can tell apart.
+    TooLong {
+        instructions: usize,
+    },
+    Instruction {
+        index: usize,
+        error: DecodeError,
+    },
+}
+
+impl Display for ImageError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ImageError::Truncated { len } => write!(
+                f,
+                "An image of {len} bytes ends partway through an instruction of {INSTRUCTION_BYTES}"
+            ),
+            ImageError::TooLong { instructions } => write!(
+                f,
+                "An image of {instructions} instructions is longer than the {} a program can have",
+                MAX_INSTRUCTIONS
+            ),
+            ImageError::Instruction { index, error } => write!(f, "{error} in instruction {index}"),
+        }
+    }
+}
+
+pub fn encode(instruction: &Instruction) -> [u8; INSTRUCTION_BYTES] {
+    let (opcode, a, b, c, immediate) = match *instruction {
+        Instruction::Negate { register } => (0, register, 0, 0, 0),
+        Instruction::AndI { register, constant } => (1, register, 0, 0, constant),
+        Instruction::Jump { instruction } => (2, 0, 0, 0, instruction.into()),
+        Instruction::JumpIf { instruction, test } => (3, test, 0, 0, instruction.into()),
+        Instruction::StoreI { constant, register } => (4, register, 0, 0, constant),
+        Instruction::Copy { src, dest } => (5, dest, src, 0, 0),
+        Instruction::Subtract { lhs, rhs, dest } => (6, dest, lhs, rhs, 0),
+        Instruction::SubtractI { register, constant } => (7, register, 0, 0, constant),
+        Instruction::Add { lhs, rhs, dest } => (8, dest, lhs, rhs, 0),
+        Instruction::AddI { register, constant } => (9, register, 0, 0, constant),
+        Instruction::ShiftLeft { register, amount } => (10, register, amount, 0, 0),
+        Instruction::ShiftRight { register, amount } => (11, register, amount, 0, 0),
+        Instruction::ShiftRightArithmetic { register, amount } => (12, register, amount, 0, 0),
+        Instruction::Store { register, slot } => (13, register, 0, 0, slot.into()),
+        Instruction::Load { slot, register } => (14, register, 0, 0, slot.into()),
+        Instruction::Trap { kind } => (15, trap_code(kind), 0, 0, 0),
+    };
+    let [d, e, f, g] = RegisterContent::to_le_bytes(immediate);
+    [opcode, a, b, c, d, e, f, g]
+}
+
+pub fn decode(bytes: [u8; INSTRUCTION_BYTES]) -> Result<Instruction, DecodeError> {
+    let [opcode, a, b, c, d, e, f, g] = bytes;
+    let immediate = RegisterContent::from_le_bytes([d, e, f, g]);
+    let label = || Label::try_from(immediate).map_err(|_| DecodeError::InvalidOperand);
+    let instruction = match opcode {
+        0 => Instruction::Negate { register: a },
+        1 => Instruction::AndI {
+            register: a,
+            constant: immediate,
+        },
+        2 => Instruction::Jump {
+            instruction: label()?,
+        },
+        3 => Instruction::JumpIf {
+            instruction: label()?,
+            test: a,
+        },
+        4 => Instruction::StoreI {
+            constant: immediate,
+            register: a,
+        },
+        5 => Instruction::Copy { src: b, dest: a },
+        6 => Instruction::Subtract {
+            lhs: b,
+            rhs: c,
+            dest: a,
+        },
+        7 => Instruction::SubtractI {
+            register: a,
+            constant: immediate,
+        },
+        8 => Instruction::Add {
+            lhs: b,
+            rhs: c,
+            dest: a,
+        },
+        9 => Instruction::AddI {
+            register: a,
+            constant: immediate,
+        },
+        10 => Instruction::ShiftLeft {
+            register: a,
+            amount: b,
+        },
+        11 => Instruction::ShiftRight {
+            register: a,
+            amount: b,
+        },
+        12 => Instruction::ShiftRightArithmetic {
+            register: a,
+            amount: b,
+        },
+        13 => Instruction::Store {
+            register: a,
+            slot: label()?,
+        },
+        14 => Instruction::Load {
+            slot: label()?,
+            register: a,
+        },
+        15 => Instruction::Trap {
+            kind: trap_kind(a)?,
+        },
+        _ => return Err(DecodeError::UnknownOpcode(opcode)),
+    };
+    // Anything the instruction does not use has to be zero
+    if encode(&instruction) != bytes {
+        return Err(DecodeError::InvalidOperand);
+    }
+    Ok(instruction)
+}
+
+/// `program` as a raw image.
+pub fn encode_program(program: &[Instruction]) -> Vec<u8> {
+    program.iter().flat_map(encode).collect()
+}
+
+/// The program in a raw image.
+pub fn decode_program(image: &[u8]) -> Result<Vec<Instruction>, ImageError> {
+    let chunks = image.chunks_exact(INSTRUCTION_BYTES);
+    if !chunks.remainder().is_empty() {
+        return Err(ImageError::Truncated { len: image.len() });
+    }
+    if chunks.len() > MAX_INSTRUCTIONS {
+        return Err(ImageError::TooLong {
+            instructions: chunks.len(),
+        });
+    }
+    chunks
+        .enumerate()
+        .map(|(index, bytes)| {
+            let bytes = bytes
+                .try_into()
+                .expect("chunks are exactly one instruction");
+            decode(bytes).map_err(|error| ImageError::Instruction { index, error })
+        })
+        .collect()
+}
+
+fn trap_code(kind: TrapKind) -> u8 {
+    match kind {
+        TrapKind::DivisionByZero => 0,
+    }
+}
+
+fn trap_kind(code: u8) -> Result<TrapKind, DecodeError> {
+    match code {
+        0 => Ok(TrapKind::DivisionByZero),
+        _ => Err(DecodeError::UnknownTrap(code)),
+    }
+}
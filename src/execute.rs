@@ -1,19 +1,27 @@
+use crate::compile::{REMAINDER_REGISTER, RESULT_REGISTER};
+use crate::trace::{NoTracer, Tracer};
 use std::fmt::{Display, Formatter};
 
 pub type Label = u16;
 
-type Register = u8;
-pub type RegisterContent = i16;
+pub type Register = u8;
+pub type RegisterContent = i32;
+pub type Slot = u16;
+
+/// Registers a `Machine` has unless asked for a different amount.
+pub const DEFAULT_REGISTERS: u8 = 5;
+/// Instructions a `Machine` executes before it pauses a program that does not halt.
+pub const DEFAULT_FUEL: u64 = 1 << 20;
 
 //Execution
-#[derive(Clone, Debug)]
-// #[repr(C)] if we want to bit match :P : 24bit length instructions (max '3 * Register' or 'register + Label') + something to know the variant
-pub enum Instruction {
+#[derive(Clone, Debug, Eq, PartialEq)]
+// Eight bytes each in binary, see `encoding`
+pub enum Instruction<R = Register> {
     Negate {
-        register: Register,
+        register: R,
     },
     AndI {
-        register: Register,
+        register: R,
         constant: RegisterContent,
     },
     Jump {
@@ -21,152 +29,732 @@ pub enum Instruction {
     },
     JumpIf {
         instruction: Label,
-        test: Register,
+        test: R,
     }, //Jump if zero in test register
     StoreI {
         constant: RegisterContent,
-        register: Register,
+        register: R,
     },
     Copy {
-        src: Register,
-        dest: Register,
+        src: R,
+        dest: R,
     },
     Subtract {
-        lhs: Register,
-        rhs: Register,
-        dest: Register,
+        lhs: R,
+        rhs: R,
+        dest: R,
     },
     SubtractI {
-        register: Register,
+        register: R,
         constant: RegisterContent,
     },
     Add {
-        lhs: Register,
-        rhs: Register,
-        dest: Register,
+        lhs: R,
+        rhs: R,
+        dest: R,
     },
     AddI {
-        register: Register,
+        register: R,
         constant: RegisterContent,
     },
     ShiftLeft {
-        register: Register,
+        register: R,
         amount: u8,
     },
     ShiftRight {
-        register: Register,
+        register: R,
+        amount: u8,
+    },
+    /// `ShiftRight` that copies the sign bit into the vacated bits.
+    ShiftRightArithmetic {
+        register: R,
         amount: u8,
     },
+    Store {
+        register: R,
+        slot: Slot,
+    },
+    Load {
+        slot: Slot,
+        register: R,
+    },
+    /// Stops the machine with a `MachineError::Trap`.
+    Trap {
+        kind: TrapKind,
+    },
+}
+
+/// Why a program stopped the machine on purpose.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum TrapKind {
+    DivisionByZero,
+}
+
+impl TrapKind {
+    /// The name the disassembly uses for this kind of trap.
+    pub fn mnemonic(&self) -> &'static str {
+        match self {
+            TrapKind::DivisionByZero => "divbyzero",
+        }
+    }
+}
+
+impl Display for TrapKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TrapKind::DivisionByZero => write!(f, "Division by zero"),
+        }
+    }
+}
+
+/// What a `Machine` does when `Add`, `Subtract` or their immediate forms overflow.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Overflow {
+    /// Two's complement wrap around.
+    Wrap,
+    /// Stop with `MachineError::Overflow`.
+    Trap,
+}
+
+/// Why a `Machine` stopped before running off the end of its program.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum MachineError {
+    Trap {
+        kind: TrapKind,
+        instruction: Label,
+    },
+    /// A jump went past the end of the program, jumping to the end itself halts. Running on from
+    /// the last instruction a `Label` can count to is out of range too, with `pc` at it.
+    PcOutOfRange {
+        pc: Label,
+    },
+    InvalidRegister {
+        register: Register,
+        instruction: Label,
+    },
+    Overflow {
+        instruction: Label,
+    },
+    /// The machine ran out of fuel after `limit` instructions, `Machine::resume` carries on.
+    StepLimitExceeded {
+        limit: u64,
+    },
+}
+
+impl MachineError {
+    /// The instruction that went wrong, if it was one in particular.
+    pub fn instruction(&self) -> Option<Label> {
+        match *self {
+            MachineError::Trap { instruction, .. }
+            | MachineError::InvalidRegister { instruction, .. }
+            | MachineError::Overflow { instruction } => Some(instruction),
+            MachineError::PcOutOfRange { .. } | MachineError::StepLimitExceeded { .. } => None,
+        }
+    }
+}
+
+impl Display for MachineError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MachineError::Trap { kind, instruction } => {
+                write!(f, "{kind} trapped at instruction {instruction}")
+            }
+            MachineError::PcOutOfRange { pc } => {
+                write!(f, "Jumped to instruction {pc}, past the end of the program")
+            }
+            MachineError::InvalidRegister {
+                register,
+                instruction,
+            } => write!(
+                f,
+                "Instruction {instruction} uses register {register}, which does not exist"
+            ),
+            MachineError::Overflow { instruction } => {
+                write!(f, "Overflow trapped at instruction {instruction}")
+            }
+            MachineError::StepLimitExceeded { limit } => {
+                write!(f, "Ran out of fuel after {limit} instructions")
+            }
+        }
+    }
 }
 
-impl Instruction {
-    fn as_str(&self) -> &'static str {
+impl<R> Instruction<R> {
+    /// The name the disassembly uses for this kind of instruction.
+    pub fn mnemonic(&self) -> &'static str {
         match self {
-            Instruction::Negate { .. } => "Negate",
-            Instruction::AndI { .. } => "AndI",
-            Instruction::Jump { .. } => "Jump",
-            Instruction::JumpIf { .. } => "JumpIf",
-            Instruction::StoreI { .. } => "StoreI",
-            Instruction::Copy { .. } => "Copy",
-            Instruction::Subtract { .. } => "Subtract",
-            Instruction::SubtractI { .. } => "SubtractI",
-            Instruction::Add { .. } => "Add",
-            Instruction::AddI { .. } => "Add",
-            Instruction::ShiftLeft { .. } => "ShiftLeft",
-            Instruction::ShiftRight { .. } => "ShiftRight",
+            Instruction::Negate { .. } => "negate",
+            Instruction::AndI { .. } => "andi",
+            Instruction::Jump { .. } => "jump",
+            Instruction::JumpIf { .. } => "jumpif",
+            Instruction::StoreI { .. } => "storei",
+            Instruction::Copy { .. } => "copy",
+            Instruction::Subtract { .. } => "subtract",
+            Instruction::SubtractI { .. } => "subtracti",
+            Instruction::Add { .. } => "add",
+            Instruction::AddI { .. } => "addi",
+            Instruction::ShiftLeft { .. } => "shiftleft",
+            Instruction::ShiftRight { .. } => "shiftright",
+            Instruction::ShiftRightArithmetic { .. } => "shiftrighta",
+            Instruction::Store { .. } => "store",
+            Instruction::Load { .. } => "load",
+            Instruction::Trap { .. } => "trap",
         }
     }
 }
 
-impl Display for Instruction {
+impl<R: Copy> Instruction<R> {
+    /// Registers this instruction reads before writing anything.
+    pub fn reads(&self) -> Vec<R> {
+        match *self {
+            Instruction::Negate { register }
+            | Instruction::AndI { register, .. }
+            | Instruction::SubtractI { register, .. }
+            | Instruction::AddI { register, .. }
+            | Instruction::ShiftLeft { register, .. }
+            | Instruction::ShiftRight { register, .. }
+            | Instruction::ShiftRightArithmetic { register, .. }
+            | Instruction::Store { register, .. } => vec![register],
+            Instruction::JumpIf { test, .. } => vec![test],
+            Instruction::Copy { src, .. } => vec![src],
+            Instruction::Subtract { lhs, rhs, .. } | Instruction::Add { lhs, rhs, .. } => {
+                vec![lhs, rhs]
+            }
+            Instruction::Jump { .. }
+            | Instruction::StoreI { .. }
+            | Instruction::Load { .. }
+            | Instruction::Trap { .. } => vec![],
+        }
+    }
+
+    /// The register this instruction writes, if any.
+    pub fn writes(&self) -> Option<R> {
+        match *self {
+            Instruction::Negate { register }
+            | Instruction::AndI { register, .. }
+            | Instruction::StoreI { register, .. }
+            | Instruction::SubtractI { register, .. }
+            | Instruction::AddI { register, .. }
+            | Instruction::ShiftLeft { register, .. }
+            | Instruction::ShiftRight { register, .. }
+            | Instruction::ShiftRightArithmetic { register, .. }
+            | Instruction::Load { register, .. } => Some(register),
+            Instruction::Copy { dest, .. }
+            | Instruction::Subtract { dest, .. }
+            | Instruction::Add { dest, .. } => Some(dest),
+            Instruction::Jump { .. }
+            | Instruction::JumpIf { .. }
+            | Instruction::Store { .. }
+            | Instruction::Trap { .. } => None,
+        }
+    }
+
+    /// Where this instruction may jump to.
+    pub fn target(&self) -> Option<Label> {
+        match *self {
+            Instruction::Jump { instruction } | Instruction::JumpIf { instruction, .. } => {
+                Some(instruction)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn target_mut(&mut self) -> Option<&mut Label> {
+        match self {
+            Instruction::Jump { instruction } | Instruction::JumpIf { instruction, .. } => {
+                Some(instruction)
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether the next instruction can run after this one.
+    pub fn falls_through(&self) -> bool {
+        !matches!(self, Instruction::Jump { .. } | Instruction::Trap { .. })
+    }
+
+    /// The same instruction with every register replaced by `f(register)`.
+    pub fn map_registers<S>(self, mut f: impl FnMut(R) -> S) -> Instruction<S> {
+        match self {
+            Instruction::Negate { register } => Instruction::Negate {
+                register: f(register),
+            },
+            Instruction::AndI { register, constant } => Instruction::AndI {
+                register: f(register),
+                constant,
+            },
+            Instruction::Jump { instruction } => Instruction::Jump { instruction },
+            Instruction::JumpIf { instruction, test } => Instruction::JumpIf {
+                instruction,
+                test: f(test),
+            },
+            Instruction::StoreI { constant, register } => Instruction::StoreI {
+                constant,
+                register: f(register),
+            },
+            Instruction::Copy { src, dest } => Instruction::Copy {
+                src: f(src),
+                dest: f(dest),
+            },
+            Instruction::Subtract { lhs, rhs, dest } => Instruction::Subtract {
+                lhs: f(lhs),
+                rhs: f(rhs),
+                dest: f(dest),
+            },
+            Instruction::SubtractI { register, constant } => Instruction::SubtractI {
+                register: f(register),
+                constant,
+            },
+            Instruction::Add { lhs, rhs, dest } => Instruction::Add {
+                lhs: f(lhs),
+                rhs: f(rhs),
+                dest: f(dest),
+            },
+            Instruction::AddI { register, constant } => Instruction::AddI {
+                register: f(register),
+                constant,
+            },
+            Instruction::ShiftLeft { register, amount } => Instruction::ShiftLeft {
+                register: f(register),
+                amount,
+            },
+            Instruction::ShiftRight { register, amount } => Instruction::ShiftRight {
+                register: f(register),
+                amount,
+            },
+            Instruction::ShiftRightArithmetic { register, amount } => {
+                Instruction::ShiftRightArithmetic {
+                    register: f(register),
+                    amount,
+                }
+            }
+            Instruction::Store { register, slot } => Instruction::Store {
+                register: f(register),
+                slot,
+            },
+            Instruction::Load { slot, register } => Instruction::Load {
+                slot,
+                register: f(register),
+            },
+            Instruction::Trap { kind } => Instruction::Trap { kind },
+        }
+    }
+}
+
+/// Disassembly with whatever gets written first, `addi r4, 15` or `add r2, r0, r1`. Jump targets
+/// are written `@7` and memory slots `[3]`.
+impl<R: Display> Display for Instruction<R> {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        let dsp = self.as_str();
-        write!(f, "{}", dsp)
+        let mnemonic = self.mnemonic();
+        match self {
+            Instruction::Negate { register } => write!(f, "{mnemonic} r{register}"),
+            Instruction::AndI { register, constant }
+            | Instruction::StoreI { constant, register }
+            | Instruction::SubtractI { register, constant }
+            | Instruction::AddI { register, constant } => {
+                write!(f, "{mnemonic} r{register}, {constant}")
+            }
+            Instruction::ShiftLeft { register, amount }
+            | Instruction::ShiftRight { register, amount }
+            | Instruction::ShiftRightArithmetic { register, amount } => {
+                write!(f, "{mnemonic} r{register}, {amount}")
+            }
+            Instruction::Jump { instruction } => write!(f, "{mnemonic} @{instruction}"),
+            Instruction::JumpIf { instruction, test } => {
+                write!(f, "{mnemonic} r{test}, @{instruction}")
+            }
+            Instruction::Copy { src, dest } => write!(f, "{mnemonic} r{dest}, r{src}"),
+            Instruction::Subtract { lhs, rhs, dest } | Instruction::Add { lhs, rhs, dest } => {
+                write!(f, "{mnemonic} r{dest}, r{lhs}, r{rhs}")
+            }
+            Instruction::Store { register, slot } => write!(f, "{mnemonic} [{slot}], r{register}"),
+            Instruction::Load { slot, register } => write!(f, "{mnemonic} r{register}, [{slot}]"),
+            Instruction::Trap { kind } => write!(f, "{mnemonic} {}", kind.mnemonic()),
+        }
     }
 }
 
-pub struct Machine {
-    registers: [RegisterContent; 5],
+pub struct Machine<T = NoTracer> {
+    registers: Vec<RegisterContent>,
+    memory: Vec<RegisterContent>,
     pc: u16,
+    overflow: Overflow,
+    program: Vec<Instruction>,
+    /// Instructions each run may execute.
+    fuel: u64,
+    /// Instructions the current run may still execute.
+    fuel_left: u64,
+    /// Instructions the current run has executed.
+    steps: u64,
+    /// What every step of the current run changed, if the machine keeps track.
+    history: Option<Vec<Delta>>,
+    tracer: T,
+}
+
+/// What one step changed, enough to undo it.
+#[derive(Debug, Clone, Eq, PartialEq)]
+struct Delta {
+    /// Where the pc was before the step.
+    pc: Label,
+    /// Registers written during the step and what they held before, in the order they were written.
+    registers: Vec<(Register, RegisterContent)>,
+    /// The memory slot the step stored to and what it held before.
+    memory: Option<(Slot, RegisterContent)>,
+    /// How much memory there was before the step.
+    memory_len: usize,
+}
+
+/// Whether a `Machine` has more of its program to run.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Status {
+    Running,
+    Halted(RunOutcome),
+}
+
+/// What a finished run amounts to.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct RunOutcome {
+    /// Instructions executed, jumps included.
+    pub steps: u64,
+}
+
+impl Default for Machine {
+    fn default() -> Self {
+        Self::with_registers(DEFAULT_REGISTERS)
+    }
 }
 
 impl Machine {
-    pub fn new() -> Self {
+    pub fn with_registers(count: u8) -> Self {
         Self {
-            registers: [0; 5],
+            registers: vec![0; count as usize],
+            memory: Vec::new(),
             pc: 0,
+            overflow: Overflow::Wrap,
+            program: Vec::new(),
+            fuel: DEFAULT_FUEL,
+            fuel_left: 0,
+            steps: 0,
+            history: None,
+            tracer: NoTracer,
+        }
+    }
+}
+
+impl<T: Tracer> Machine<T> {
+    /// The same machine reporting to `tracer` instead.
+    pub fn tracer<U: Tracer>(self, tracer: U) -> Machine<U> {
+        Machine {
+            registers: self.registers,
+            memory: self.memory,
+            pc: self.pc,
+            overflow: self.overflow,
+            program: self.program,
+            fuel: self.fuel,
+            fuel_left: self.fuel_left,
+            steps: self.steps,
+            history: self.history,
+            tracer,
         }
     }
 
+    pub fn fuel(mut self, fuel: u64) -> Self {
+        self.fuel = fuel;
+        self
+    }
+
+    pub fn overflow(mut self, overflow: Overflow) -> Self {
+        self.overflow = overflow;
+        self
+    }
+
+    /// Keeps a log of what every step changes, so runs can be rewound and register histories
+    /// looked up.
+    pub fn record(mut self) -> Self {
+        self.history = Some(Vec::new());
+        self
+    }
+
     pub fn answer_by_convention(&self) -> RegisterContent {
-        self.registers[3]
+        self.registers[RESULT_REGISTER as usize]
+    }
+
+    /// The remainder left by code from `Compiler::compile_divmod`.
+    pub fn remainder_by_convention(&self) -> RegisterContent {
+        self.registers[REMAINDER_REGISTER as usize]
     }
 
-    pub fn run(&mut self, program: Vec<Instruction>) {
+    /// Runs `program` from its first instruction until it jumps to or runs off its end, or the
+    /// fuel runs out.
+    pub fn run(&mut self, program: Vec<Instruction>) -> Result<RunOutcome, MachineError> {
+        self.load(program);
+        self.proceed()
+    }
+
+    /// Sets up `program` to run from its first instruction without running any of it.
+    pub fn load(&mut self, program: Vec<Instruction>) {
+        self.program = program;
         self.pc = 0;
+        self.steps = 0;
+        self.fuel_left = self.fuel;
+        if let Some(history) = &mut self.history {
+            history.clear();
+        }
+    }
+
+    /// Carries on where the last run stopped with `fuel` more instructions to go.
+    pub fn resume(&mut self, fuel: u64) -> Result<RunOutcome, MachineError> {
+        self.add_fuel(fuel);
+        self.proceed()
+    }
+
+    pub fn add_fuel(&mut self, fuel: u64) {
+        self.fuel_left = self.fuel_left.saturating_add(fuel);
+    }
+
+    fn proceed(&mut self) -> Result<RunOutcome, MachineError> {
         loop {
-            let instruction = &program[self.pc as usize];
+            if let Status::Halted(outcome) = self.step()? {
+                return Ok(outcome);
+            }
+        }
+    }
 
-            match instruction {
-                Instruction::Negate { register } => {
-                    self.registers[*register as usize] = !self.registers[*register as usize]
-                    //^ *constant;
-                }
-                Instruction::AndI { register, constant } => {
-                    self.registers[*register as usize] &= constant;
-                }
-                Instruction::Jump { instruction } => {
-                    self.pc = *instruction;
-                    continue;
-                }
-                Instruction::JumpIf { instruction, test } => {
-                    if self.registers[*test as usize] != 0 {
-                        self.pc = *instruction;
-                        continue;
-                    }
-                }
-                Instruction::StoreI { constant, register } => {
-                    self.registers[*register as usize] = *constant;
-                }
-                Instruction::Copy { src, dest } => {
-                    self.registers[*dest as usize] = self.registers[*src as usize];
-                }
-                Instruction::Subtract { lhs, rhs, dest } => {
-                    self.registers[*dest as usize] =
-                        self.registers[*lhs as usize] - self.registers[*rhs as usize];
-                }
-                Instruction::Add { lhs, rhs, dest } => {
-                    self.registers[*dest as usize] =
-                        self.registers[*lhs as usize] + self.registers[*rhs as usize];
-                }
-                Instruction::AddI { register, constant } => {
-                    self.registers[*register as usize] += *constant;
-                }
-                Instruction::ShiftLeft { register, amount } => {
-                    self.registers[*register as usize] <<= amount;
+    /// Runs the instruction at the pc, unless the program has already ended.
+    pub fn step(&mut self) -> Result<Status, MachineError> {
+        if self.pc as usize == self.program.len() {
+            self.tracer.halt(self.steps);
+            return Ok(Status::Halted(RunOutcome { steps: self.steps }));
+        }
+        let Some(instruction) = self.program.get(self.pc as usize).cloned() else {
+            return Err(MachineError::PcOutOfRange { pc: self.pc });
+        };
+        if self.fuel_left == 0 {
+            return Err(MachineError::StepLimitExceeded { limit: self.steps });
+        }
+        self.fuel_left -= 1;
+        self.steps += 1;
+
+        let pc = self.pc;
+        if let Some(history) = &mut self.history {
+            history.push(Delta {
+                pc,
+                registers: Vec::new(),
+                memory: None,
+                memory_len: self.memory.len(),
+            });
+        }
+        self.tracer.before(pc, &instruction, &self.registers);
+        match self.execute(&instruction)? {
+            Some(target) => {
+                self.tracer.jump(pc, target);
+                self.pc = target;
+            }
+            None => {
+                // Falling off a program as long as a `Label` can count
+                self.pc = self
+                    .pc
+                    .checked_add(1)
+                    .ok_or(MachineError::PcOutOfRange { pc: self.pc })?;
+            }
+        }
+        self.tracer.after(pc, &instruction, &self.registers);
+        Ok(Status::Running)
+    }
+
+    /// Undoes the last step, returning false if there is none on record.
+    pub fn step_back(&mut self) -> bool {
+        let Some(delta) = self.history.as_mut().and_then(Vec::pop) else {
+            return false;
+        };
+        for &(register, value) in delta.registers.iter().rev() {
+            self.registers[register as usize] = value;
+        }
+        if let Some((slot, value)) = delta.memory {
+            if let Some(slot) = self.memory.get_mut(slot as usize) {
+                *slot = value;
+            }
+        }
+        self.memory.truncate(delta.memory_len);
+        self.pc = delta.pc;
+        self.steps -= 1;
+        self.fuel_left += 1;
+        true
+    }
+
+    /// Undoes steps until only `step` of the current run are left, returning false and leaving
+    /// the machine as it is if those are not all on record.
+    pub fn rewind(&mut self, step: u64) -> bool {
+        let recorded = self.history.as_ref().map_or(0, Vec::len) as u64;
+        if step > self.steps || self.steps - step > recorded {
+            return false;
+        }
+        while self.steps > step {
+            self.step_back();
+        }
+        true
+    }
+
+    /// What `register` held after each recorded step, starting with the value before the first
+    /// of them. Changes made with `set_register` count towards the step before them.
+    pub fn register_history(&self, register: Register) -> Option<Vec<RegisterContent>> {
+        let history = self.history.as_ref()?;
+        let mut value = *self.registers.get(register as usize)?;
+        let mut values = vec![value];
+        for delta in history.iter().rev() {
+            if let Some(&(_, before)) = delta.registers.iter().find(|(r, _)| *r == register) {
+                value = before;
+            }
+            values.push(value);
+        }
+        values.reverse();
+        Some(values)
+    }
+
+    /// Instructions the current run has executed so far.
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    pub fn pc(&self) -> Label {
+        self.pc
+    }
+
+    pub fn program(&self) -> &[Instruction] {
+        &self.program
+    }
+
+    pub fn registers(&self) -> &[RegisterContent] {
+        &self.registers
+    }
+
+    pub fn set_register(
+        &mut self,
+        register: Register,
+        value: RegisterContent,
+    ) -> Result<(), MachineError> {
+        self.write(register, value)
+    }
+
+    /// Carries out one instruction, returning where it jumps to if it does.
+    fn execute(&mut self, instruction: &Instruction) -> Result<Option<Label>, MachineError> {
+        match *instruction {
+            Instruction::Negate { register } => {
+                let value = self.read(register)?;
+                self.write(register, !value)?;
+            }
+            Instruction::AndI { register, constant } => {
+                let value = self.read(register)?;
+                self.write(register, value & constant)?;
+            }
+            Instruction::Jump { instruction } => return Ok(Some(instruction)),
+            Instruction::JumpIf { instruction, test } => {
+                if self.read(test)? != 0 {
+                    return Ok(Some(instruction));
                 }
-                Instruction::ShiftRight { register, amount } => {
-                    self.registers[*register as usize] =
-                        (self.registers[*register as usize] as u16 >> amount) as RegisterContent;
+            }
+            Instruction::StoreI { constant, register } => self.write(register, constant)?,
+            Instruction::Copy { src, dest } => {
+                let value = self.read(src)?;
+                self.write(dest, value)?;
+            }
+            Instruction::Subtract { lhs, rhs, dest } => {
+                let (lhs, rhs) = (self.read(lhs)?, self.read(rhs)?);
+                let value = self.arithmetic(lhs.overflowing_sub(rhs))?;
+                self.write(dest, value)?;
+            }
+            Instruction::Add { lhs, rhs, dest } => {
+                let (lhs, rhs) = (self.read(lhs)?, self.read(rhs)?);
+                let value = self.arithmetic(lhs.overflowing_add(rhs))?;
+                self.write(dest, value)?;
+            }
+            Instruction::AddI { register, constant } => {
+                let value = self.read(register)?;
+                let value = self.arithmetic(value.overflowing_add(constant))?;
+                self.write(register, value)?;
+            }
+            Instruction::SubtractI { register, constant } => {
+                let value = self.read(register)?;
+                let value = self.arithmetic(value.overflowing_sub(constant))?;
+                self.write(register, value)?;
+            }
+            // Shifts move bits, they don't overflow, bits shifted out are gone
+            Instruction::ShiftLeft { register, amount } => {
+                let value = self.read(register)?;
+                let value = value.checked_shl(amount as u32).unwrap_or(0);
+                self.write(register, value)?;
+            }
+            Instruction::ShiftRight { register, amount } => {
+                let value = self.read(register)? as u32;
+                let value = value.checked_shr(amount as u32).unwrap_or(0);
+                self.write(register, value as RegisterContent)?;
+            }
+            Instruction::ShiftRightArithmetic { register, amount } => {
+                let value = self.read(register)?;
+                let value = value >> (amount as u32).min(RegisterContent::BITS - 1);
+                self.write(register, value)?;
+            }
+            Instruction::Store { register, slot } => {
+                let value = self.read(register)?;
+                let index = slot as usize;
+                if index >= self.memory.len() {
+                    self.memory.resize(index + 1, 0);
                 }
-                Instruction::SubtractI { register, constant } => {
-                    self.registers[*register as usize] -= *constant;
+                let before = std::mem::replace(&mut self.memory[index], value);
+                if let Some(delta) = self.history.as_mut().and_then(|history| history.last_mut()) {
+                    delta.memory = Some((slot, before));
                 }
             }
-
-            println!(
-                "|{:^3?}|{:^12}| {:08b} | {:08b} | {:08b} | (test: {:08b})",
-                self.registers[0],
-                &program[self.pc as usize].as_str(),
-                self.registers[1],
-                self.registers[2],
-                self.registers[3],
-                self.registers[4],
-            );
-            self.pc += 1;
-            if self.pc == program.len() as u16 {
-                break;
+            Instruction::Load { slot, register } => {
+                let value = self.memory.get(slot as usize).copied().unwrap_or(0);
+                self.write(register, value)?;
+            }
+            Instruction::Trap { kind } => {
+                return Err(MachineError::Trap {
+                    kind,
+                    instruction: self.pc,
+                });
             }
         }
+        Ok(None)
+    }
+
+    fn read(&self, register: Register) -> Result<RegisterContent, MachineError> {
+        self.registers
+            .get(register as usize)
+            .copied()
+            .ok_or(MachineError::InvalidRegister {
+                register,
+                instruction: self.pc,
+            })
+    }
+
+    fn write(&mut self, register: Register, value: RegisterContent) -> Result<(), MachineError> {
+        let instruction = self.pc;
+        let slot =
+            self.registers
+                .get_mut(register as usize)
+                .ok_or(MachineError::InvalidRegister {
+                    register,
+                    instruction,
+                })?;
+        let before = std::mem::replace(slot, value);
+        if let Some(delta) = self.history.as_mut().and_then(|history| history.last_mut()) {
+            delta.registers.push((register, before));
+        }
+        Ok(())
+    }
+
+    /// The wrapped result of an overflowing operation, or an error if overflow traps.
+    fn arithmetic(
+        &self,
+        (value, overflowed): (RegisterContent, bool),
+    ) -> Result<RegisterContent, MachineError> {
+        match (overflowed, self.overflow) {
+            (true, Overflow::Trap) => Err(MachineError::Overflow {
+                instruction: self.pc,
+            }),
+            _ => Ok(value),
+        }
     }
 }
@@ -0,0 +1,192 @@
+//! Intel HEX for the raw image of a program, as loaded onto hardware.
+//!
+//! Every line is a record, `:` then in hex digits the number of data bytes, a 16 bit address, the
+//! record type, the data bytes and a checksum that makes all the bytes of the record add up to
+//! zero. The image is written from address 0 in data records of `RECORD_BYTES`, with an extended
+//! linear address record giving the upper 16 bits of the address whenever the data goes past a
+//! multiple of 64 KiB, and ends with an end of file record.
+
+use crate::encoding::{self, ImageError};
+use crate::execute::Instruction;
+use std::fmt::{Display, Formatter, Write};
+
+/// Data bytes in a full data record, two instructions.
+const RECORD_BYTES: usize = 16;
+
+const DATA: u8 = 0x00;
+const END_OF_FILE: u8 = 0x01;
+const EXTENDED_LINEAR_ADDRESS: u8 = 0x04;
+
+/// Why text is not the Intel HEX of a program. Lines count from 1.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum HexError {
+    /// The line is not `:` and an even number of hex digits, or its length is not the one it
+    /// gives.
+    Malformed {
+        line: usize,
+    },
+    Checksum {
+        line: usize,
+        expected: u8,
+        found: u8,
+    },
+    UnsupportedRecord {
+        line: usize,
+        kind: u8,
+    },
+    /// Data does not carry on from where the data before it ended, so the image would have a gap
+    /// or bytes written twice.
+    Address {
+        line: usize,
+        expected: u32,
+        found: u32,
+    },
+    /// There is no end of file record.
+    MissingEnd,
+    /// A record after the end of file record.
+    AfterEnd {
+        line: usize,
+    },
+    Image(ImageError),
+}
+
+impl Display for HexError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HexError::Malformed { line } => write!(f, "Line {line} is not an Intel HEX record"),
+            HexError::Checksum {
+                line,
+                expected,
+                found,
+            } => write!(
+                f,
+                "Line {line} has checksum {found:02X}, but its bytes need {expected:02X}"
+            ),
+            HexError::UnsupportedRecord { line, kind } => {
+                write!(f, "Line {line} has a record of unsupported type {kind:02X}")
+            }
+            HexError::Address {
+                line,
+                expected,
+                found,
+            } => write!(
+                f,
+                "Line {line} has data for address {found:#X}, but the image goes on at {expected:#X}"
+            ),
+            HexError::MissingEnd => write!(f, "There is no end of file record"),
+            HexError::AfterEnd { line } => write!(f, "Line {line} comes after the end of file"),
+            HexError::Image(error) => write!(f, "{error}"),
+        }
+    }
+}
+
+/// The raw image of `program` as Intel HEX.
+pub fn encode(program: &[Instruction]) -> String {
+    let image = encoding::encode_program(program);
+    let mut hex = String::new();
+    for (index, chunk) in image.chunks(RECORD_BYTES).enumerate() {
+        let address = index * RECORD_BYTES;
+        if address > 0 && address.is_multiple_of(0x10000) {
+            let upper = (address >> 16) as u16;
+            record(&mut hex, EXTENDED_LINEAR_ADDRESS, 0, &upper.to_be_bytes());
+        }
+        record(&mut hex, DATA, address as u16, chunk);
+    }
+    record(&mut hex, END_OF_FILE, 0, &[]);
+    hex
+}
+
+/// The program in Intel HEX as written by `encode`.
+pub fn decode(hex: &str) -> Result<Vec<Instruction>, HexError> {
+    let mut image = Vec::new();
+    let mut upper = 0;
+    let mut ended = false;
+    for (line, text) in (1..).zip(hex.lines()) {
+        let text = text.trim();
+        if text.is_empty() {
+            continue;
+        }
+        if ended {
+            return Err(HexError::AfterEnd { line });
+        }
+        let bytes = parse_record(text).ok_or(HexError::Malformed { line })?;
+        let (&found, body) = bytes
+            .split_last()
+            .expect("records have at least five bytes");
+        let expected = checksum(body);
+        if found != expected {
+            return Err(HexError::Checksum {
+                line,
+                expected,
+                found,
+            });
+        }
+        let [len, high, low, kind, data @ ..] = body else {
+            unreachable!("records have at least five bytes");
+        };
+        if data.len() != *len as usize {
+            return Err(HexError::Malformed { line });
+        }
+        let offset = u16::from_be_bytes([*high, *low]);
+        match (*kind, data) {
+            (DATA, _) => {
+                let found = upper << 16 | u32::from(offset);
+                let expected = image.len() as u32;
+                if found != expected {
+                    return Err(HexError::Address {
+                        line,
+                        expected,
+                        found,
+                    });
+                }
+                image.extend(data);
+            }
+            (END_OF_FILE, []) => ended = true,
+            (EXTENDED_LINEAR_ADDRESS, &[high, low]) if offset == 0 => {
+                upper = u16::from_be_bytes([high, low]).into();
+            }
+            (END_OF_FILE | EXTENDED_LINEAR_ADDRESS, _) => return Err(HexError::Malformed { line }),
+            (kind, _) => return Err(HexError::UnsupportedRecord { line, kind }),
+        }
+    }
+    if !ended {
+        return Err(HexError::MissingEnd);
+    }
+    encoding::decode_program(&image).map_err(HexError::Image)
+}
+
+fn record(hex: &mut String, kind: u8, address: u16, data: &[u8]) {
+    let mut bytes = vec![data.len() as u8];
+    bytes.extend(address.to_be_bytes());
+    bytes.push(kind);
+    bytes.extend(data);
+    bytes.push(checksum(&bytes));
+    hex.push(':');
+    for byte in bytes {
+        write!(hex, "{byte:02X}").expect("writing to a String cannot fail");
+    }
+    hex.push('\n');
+}
+
+/// The bytes of a record after its `:`, checksum included, if it has enough for one.
+fn parse_record(text: &str) -> Option<Vec<u8>> {
+    let digits = text.strip_prefix(':')?;
+    if digits.len() % 2 != 0
+        || digits.len() < 10
+        || !digits.bytes().all(|digit| digit.is_ascii_hexdigit())
+    {
+        return None;
+    }
+    (0..digits.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(&digits[index..index + 2], 16).ok())
+        .collect()
+}
+
+/// The byte that makes `bytes` add up to zero.
+fn checksum(bytes: &[u8]) -> u8 {
+    bytes
+        .iter()
+        .fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+        .wrapping_neg()
+}
@@ -0,0 +1,107 @@
+use crate::execute::{Instruction, Label};
+use std::fmt::{Display, Formatter};
+
+/// Why a program could not be linked.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum LinkError {
+    /// A jump goes to a label that was never defined.
+    Undefined {
+        label: Label,
+    },
+    DefinedTwice {
+        label: Label,
+    },
+    /// More labels were handed out than a `Label` can tell apart.
+    TooManyLabels,
+    /// A label was defined at an instruction index that does not fit in a `Label`.
+    OutOfRange {
+        label: Label,
+        at: usize,
+    },
+}
+
+impl Display for LinkError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LinkError::Undefined { label } => write!(f, "Label {label} is not defined"),
+            LinkError::DefinedTwice { label } => write!(f, "Label {label} is defined twice"),
+            LinkError::TooManyLabels => write!(f, "More than {} labels", Label::MAX as usize + 1),
+            LinkError::OutOfRange { label, at } => write!(
+                f,
+                "Label {label} is at instruction {at}, past the last one a jump can reach"
+            ),
+        }
+    }
+}
+
+/// Jump targets handed out before the code they point at exists.
+///
+/// Until a program is linked the targets of its jumps are labels from here rather than
+/// instruction indices, so code can be added or moved without recounting any of them.
+#[derive(Debug, Default)]
+pub struct Labels {
+    /// The instruction each label stands for, once it is defined.
+    definitions: Vec<Option<Label>>,
+    /// What went wrong handing out and defining labels, reported by `link`.
+    errors: Vec<LinkError>,
+}
+
+impl Labels {
+    /// A label that is not defined yet.
+    pub fn label(&mut self) -> Label {
+        let Ok(label) = Label::try_from(self.definitions.len()) else {
+            if !self.errors.contains(&LinkError::TooManyLabels) {
+                self.errors.push(LinkError::TooManyLabels);
+            }
+            return Label::MAX;
+        };
+        self.definitions.push(None);
+        label
+    }
+
+    /// Makes `label` stand for the instruction at index `at`.
+    pub fn define(&mut self, label: Label, at: usize) {
+        let Ok(index) = Label::try_from(at) else {
+            self.errors.push(LinkError::OutOfRange { label, at });
+            return;
+        };
+        match self.definitions.get_mut(label as usize) {
+            Some(Some(_)) => self.errors.push(LinkError::DefinedTwice { label }),
+            Some(definition) => *definition = Some(index),
+            None => {}
+        }
+    }
+
+    /// `program` with every jump pointed at the instruction its label stands for.
+    pub fn link<R: Copy>(
+        &self,
+        mut program: Vec<Instruction<R>>,
+    ) -> Result<Vec<Instruction<R>>, Vec<LinkError>> {
+        let mut errors = self.errors.clone();
+        for instruction in &mut program {
+            let Some(target) = instruction.target_mut() else {
+                continue;
+            };
+            match self.definitions.get(*target as usize).copied().flatten() {
+                Some(index) => *target = index,
+                None => {
+                    let label = *target;
+                    // A label defined out of range is not undefined as well
+                    let reported = errors.iter().any(|error| match *error {
+                        LinkError::Undefined { label: other }
+                        | LinkError::OutOfRange { label: other, .. } => other == label,
+                        _ => false,
+                    });
+                    if !reported {
+                        errors.push(LinkError::Undefined { label });
+                    }
+                }
+            }
+        }
+        if errors.is_empty() {
+            Ok(program)
+        } else {
+            Err(errors)
+        }
+    }
+}
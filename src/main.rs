@@ -1,66 +1,482 @@
-use crate::compile::Compiler;
-use crate::execute::Machine;
+use crate::compile::{Compiled, Compiler, Division, Signedness};
+use crate::debug::Debugger;
+use crate::diagnostic::{Diagnostic, Span};
+use crate::execute::{
+    Instruction, Machine, MachineError, Overflow, DEFAULT_FUEL, DEFAULT_REGISTERS,
+};
+use crate::object::{DebugInfo, Object};
+use crate::parsing::Node;
+use crate::regalloc::MIN_REGISTERS;
+use crate::tokens::Mode;
+use crate::tokens::Token;
+use crate::tokens::TokenKind::{AddT, DivT, ModT, MulT, RParenT, SubT};
+use crate::trace::{NoTracer, TableTracer, Tracer};
+use crate::width::Width;
 use parsing::Parser::Expr;
-use std::env;
+use std::process::ExitCode;
+use std::{env, fs, io};
 
+mod asm;
 mod compile;
+mod debug;
+mod diagnostic;
+mod encoding;
 mod execute;
+mod hex;
+mod link;
+mod object;
+mod optimize;
 mod parsing;
+mod regalloc;
 mod tokens;
+mod trace;
+mod width;
 
-fn main() -> Result<(), String> {
-    let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        return Err("Need exactly one argument".to_string());
+/// Everything the command line can change about how an expression is evaluated.
+struct Options {
+    mode: Mode,
+    registers: u8,
+    width: Width,
+    signedness: Signedness,
+    division: Division,
+    overflow: Overflow,
+    fuel: u64,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            mode: Mode::Strict,
+            registers: DEFAULT_REGISTERS,
+            width: Width::default(),
+            signedness: Signedness::Unsigned,
+            division: Division::Truncated,
+            overflow: Overflow::Wrap,
+            fuel: DEFAULT_FUEL,
+        }
+    }
+}
+
+/// What to do with the expression, picked by the first argument.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Subcommand {
+    /// Print the answer, when there is no subcommand.
+    Calculate,
+    Debug,
+    /// Print the compiled program.
+    Disasm,
+    /// Run a program assembled from the file named instead of an expression.
+    Asm,
+    /// Write an object file for the expression to the file given with `-o` instead of running it.
+    Compile,
+    /// Run the object file, raw image or Intel HEX named, as written by `Compile`.
+    Run,
+}
+
+/// What `Compile` writes.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Format {
+    Object,
+    /// The instructions and nothing else, see `encoding`.
+    Raw,
+    /// The raw image as Intel HEX.
+    Hex,
+}
+
+/// Exit code for a program the machine stopped, as opposed to input that did not compile.
+const TRAP_EXIT_CODE: u8 = 2;
+
+/// Why an expression has no answer.
+#[derive(Debug, Eq, PartialEq)]
+enum Failure {
+    Diagnostics(Vec<Diagnostic>),
+    Machine(MachineError),
+}
+
+impl From<Vec<Diagnostic>> for Failure {
+    fn from(diagnostics: Vec<Diagnostic>) -> Self {
+        Failure::Diagnostics(diagnostics)
+    }
+}
+
+impl From<Diagnostic> for Failure {
+    fn from(diagnostic: Diagnostic) -> Self {
+        Failure::Diagnostics(vec![diagnostic])
+    }
+}
+
+impl From<MachineError> for Failure {
+    fn from(error: MachineError) -> Self {
+        Failure::Machine(error)
+    }
+}
+
+fn main() -> ExitCode {
+    let mut options = Options::default();
+    let mut divmod = false;
+    let mut optimize = false;
+    let mut report_steps = false;
+    let mut trace = false;
+    let mut input = None;
+    let mut output = None;
+    let mut format = Format::Object;
+    let mut strip = false;
+    let mut args = env::args().skip(1).peekable();
+    let subcommand = match args.peek().map(String::as_str) {
+        Some("debug") => Subcommand::Debug,
+        Some("disasm") => Subcommand::Disasm,
+        Some("asm") => Subcommand::Asm,
+        Some("compile") => Subcommand::Compile,
+        Some("run") => Subcommand::Run,
+        _ => Subcommand::Calculate,
+    };
+    if subcommand != Subcommand::Calculate {
+        args.next();
+    }
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--lenient" => options.mode = Mode::Lenient,
+            "--divmod" => divmod = true,
+            "--optimize" => optimize = true,
+            "--signed" => options.signedness = Signedness::Signed,
+            "--trap-overflow" => options.overflow = Overflow::Trap,
+            "--steps" => report_steps = true,
+            "--trace" => trace = true,
+            "--raw" if subcommand == Subcommand::Compile => format = Format::Raw,
+            "--hex" if subcommand == Subcommand::Compile => format = Format::Hex,
+            "--strip" if subcommand == Subcommand::Compile => strip = true,
+            "-o" if subcommand == Subcommand::Compile => match args.next() {
+                Some(path) => output = Some(path),
+                None => {
+                    eprintln!("-o needs a file to write to");
+                    return ExitCode::FAILURE;
+                }
+            },
+            "--fuel" => match args.next().and_then(|fuel| fuel.parse().ok()) {
+                Some(fuel) if fuel > 0 => options.fuel = fuel,
+                _ => {
+                    eprintln!("--fuel needs a number of instructions from 1");
+                    return ExitCode::FAILURE;
+                }
+            },
+            "--registers" => match args.next().and_then(|count| count.parse().ok()) {
+                Some(count) if count >= MIN_REGISTERS => options.registers = count,
+                _ => {
+                    eprintln!("--registers needs a number from {MIN_REGISTERS} to 255");
+                    return ExitCode::FAILURE;
+                }
+            },
+            "--division" => match args.next().as_deref() {
+                Some("truncated") => options.division = Division::Truncated,
+                Some("floored") => options.division = Division::Floored,
+                Some("euclidean") => options.division = Division::Euclidean,
+                _ => {
+                    eprintln!("--division needs one of truncated, floored or euclidean");
+                    return ExitCode::FAILURE;
+                }
+            },
+            "--width" => match args.next().and_then(|bits| Width::new(bits.parse().ok()?)) {
+                Some(width) => options.width = width,
+                None => {
+                    eprintln!("--width needs a number of bits from 1 to {}", Width::MAX);
+                    return ExitCode::FAILURE;
+                }
+            },
+            _ if input.is_none() => input = Some(arg),
+            _ => {
+                eprintln!("Need exactly one expression");
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+    let Some(input) = input else {
+        eprintln!("Need exactly one expression");
+        return ExitCode::FAILURE;
+    };
+    if subcommand == Subcommand::Compile && output.is_none() {
+        eprintln!("compile needs -o and a file to write to");
+        return ExitCode::FAILURE;
+    }
+    let (source, prepared) = match subcommand {
+        Subcommand::Asm => {
+            let source = match fs::read_to_string(&input) {
+                Ok(source) => source,
+                Err(error) => {
+                    eprintln!("Could not read {input}: {error}");
+                    return ExitCode::FAILURE;
+                }
+            };
+            let prepared = asm::assemble(&source)
+                .map(|program| (program, None))
+                .map_err(Failure::from);
+            (source, prepared)
+        }
+        Subcommand::Run => {
+            let bytes = match fs::read(&input) {
+                Ok(bytes) => bytes,
+                Err(error) => {
+                    eprintln!("Could not read {input}: {error}");
+                    return ExitCode::FAILURE;
+                }
+            };
+            match load(&bytes, &mut options) {
+                Ok(loaded) => (String::new(), Ok(loaded)),
+                Err(error) => {
+                    eprintln!("Could not load {input}: {error}");
+                    return ExitCode::FAILURE;
+                }
+            }
+        }
+        _ => {
+            let prepared = compile_expression(&input, &options, divmod).map(|compiled| {
+                let debug = DebugInfo {
+                    source: input.clone(),
+                    spans: compiled.spans,
+                };
+                (compiled.program, Some(debug))
+            });
+            (input, prepared)
+        }
+    };
+    let (mut program, mut debug) = match prepared {
+        Ok(prepared) => prepared,
+        Err(failure) => return report(failure, &source),
+    };
+    if optimize {
+        let before = program.len();
+        let origins;
+        (program, origins) = optimize::optimize(program);
+        eprintln!(
+            "Optimized away {} of {before} instructions",
+            before - program.len()
+        );
+        if let Some(debug) = &mut debug {
+            debug.spans = origins
+                .into_iter()
+                .map(|origin| debug.spans.get(origin).copied().flatten())
+                .collect();
+        }
+    }
+    let machine = machine(&options);
+
+    let (mut table, mut quiet) = (TableTracer, NoTracer);
+    let tracer: &mut dyn Tracer = if trace { &mut table } else { &mut quiet };
+    if let Some(output) = output {
+        let bytes = match format {
+            Format::Object => {
+                let object = Object {
+                    width: options.width,
+                    registers: options.registers,
+                    program,
+                    debug: debug.filter(|_| !strip),
+                };
+                object.to_bytes()
+            }
+            Format::Raw => encoding::encode_program(&program),
+            Format::Hex => hex::encode(&program).into_bytes(),
+        };
+        return match fs::write(&output, bytes) {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(error) => {
+                eprintln!("Could not write {output}: {error}");
+                ExitCode::FAILURE
+            }
+        };
+    }
+    if subcommand == Subcommand::Disasm {
+        for (index, instruction) in program.iter().enumerate() {
+            println!("{index:>4}  {instruction}");
+        }
+        return ExitCode::SUCCESS;
+    }
+    if subcommand == Subcommand::Debug {
+        let mut debugger = Debugger::new(machine.tracer(tracer), program);
+        return match debugger.repl(io::stdin().lock(), io::stdout()) {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(error) => {
+                eprintln!("{error}");
+                ExitCode::FAILURE
+            }
+        };
+    }
+
+    let mut machine = machine.tracer(tracer);
+    let mut outcome = machine.run(program);
+    while let Err(MachineError::StepLimitExceeded { limit }) = outcome {
+        if !refuel(limit, options.fuel) {
+            break;
+        }
+        outcome = machine.resume(options.fuel);
     }
-    let tokens = tokens::tokenize(&args[1])?;
-    let parsed = Expr.parse(tokens, &[])?;
-    if !parsed.0.is_empty() {
-        return Err(format!(
-            "Remaining input '{:?}' with parsed '{:?}'!",
-            parsed.0, parsed.1
-        ));
+    match outcome {
+        Ok(outcome) => {
+            let width = options.width;
+            let answer = width.format_answer(machine.answer_by_convention());
+            if divmod {
+                let remainder = width.format_answer(machine.remainder_by_convention());
+                println!("{answer} {remainder}");
+            } else {
+                println!("{answer}");
+            }
+            if report_steps {
+                println!("Took {} steps", outcome.steps);
+            }
+            ExitCode::SUCCESS
+        }
+        Err(error) => {
+            let at = error.instruction().and_then(|instruction| {
+                let debug = debug.as_ref()?;
+                Some((debug.span(instruction)?, &debug.source))
+            });
+            match at {
+                Some((span, source)) => {
+                    let diagnostic = Diagnostic::new(error.to_string(), span);
+                    eprintln!("{}", diagnostic.render(source));
+                    ExitCode::from(TRAP_EXIT_CODE)
+                }
+                None => report(error.into(), &source),
+            }
+        }
     }
-    let nodes = parsed.1;
+}
+
+/// Prints why `input` has no answer and picks the exit code for it.
+fn report(failure: Failure, input: &str) -> ExitCode {
+    match failure {
+        Failure::Diagnostics(diagnostics) => {
+            for diagnostic in diagnostics {
+                eprintln!("{}", diagnostic.render(input));
+            }
+            ExitCode::FAILURE
+        }
+        Failure::Machine(error) => {
+            eprintln!("{error}");
+            ExitCode::from(TRAP_EXIT_CODE)
+        }
+    }
+}
+
+/// Asks whether a machine that ran out of fuel after `limit` instructions gets `fuel` more.
+fn refuel(limit: u64, fuel: u64) -> bool {
+    eprint!("Paused after {limit} instructions, run another {fuel}? [y/N] ");
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer).is_ok() && answer.trim().eq_ignore_ascii_case("y")
+}
 
-    let compiled = Compiler::new(nodes).compile()?;
+/// The program for `input`, a single division for `divmod`.
+fn compile_expression(input: &str, options: &Options, divmod: bool) -> Result<Compiled, Failure> {
+    let nodes = parse(input, options)?;
+    let compiler = Compiler::new(nodes, options.width, options.signedness, options.division);
+    Ok(if divmod {
+        compiler.compile_divmod(options.registers)?
+    } else {
+        compiler.compile(options.registers)?
+    })
+}
 
-    let mut machine = Machine::new();
-    machine.run(compiled);
-    let answer = machine.answer_by_convention();
+/// The program in an object file, a raw image or Intel HEX, and its debug section if it has one.
+/// An object file also sets the width and the registers in `options`.
+fn load(
+    bytes: &[u8],
+    options: &mut Options,
+) -> Result<(Vec<Instruction>, Option<DebugInfo>), String> {
+    if bytes.starts_with(&object::MAGIC) {
+        let object = Object::from_bytes(bytes).map_err(|error| error.to_string())?;
+        options.width = object.width;
+        options.registers = object.registers;
+        Ok((object.program, object.debug))
+    } else if bytes.starts_with(b":") {
+        let hex = std::str::from_utf8(bytes).map_err(|error| error.to_string())?;
+        let program = hex::decode(hex).map_err(|error| error.to_string())?;
+        Ok((program, None))
+    } else {
+        let program = encoding::decode_program(bytes).map_err(|error| error.to_string())?;
+        Ok((program, None))
+    }
+}
+
+fn machine(options: &Options) -> Machine {
+    Machine::with_registers(options.registers)
+        .overflow(options.overflow)
+        .fuel(options.fuel)
+}
 
-    println!("{answer:08b}");
-    Ok(())
+fn parse(input: &str, options: &Options) -> Result<Vec<Node>, Vec<Diagnostic>> {
+    let tokens = tokens::tokenize(input, options.mode)?;
+    let parsed = Expr.parse(tokens, &[], options.width)?;
+    if let Some(close @ Token { kind: RParenT, .. }) = parsed.0.first() {
+        return Err(vec![Diagnostic::new("Unmatched ')'", close.span)]);
+    }
+    if let Some(first) = parsed.0.first() {
+        // Nothing parsed before an operator means its left operand is missing, not an operator
+        if parsed.1.is_empty() && matches!(first.kind, AddT | SubT | MulT | DivT | ModT) {
+            let span = Span::new(first.span.offset, 0);
+            return Err(vec![Diagnostic::new("Expected an operand", span)]);
+        }
+        return Err(vec![Diagnostic::new("Expected an operator", first.span)]);
+    }
+    if parsed.1.is_empty() {
+        return Err(vec![Diagnostic::new(
+            "Expected an operand",
+            Span::new(0, 0),
+        )]);
+    }
+    Ok(parsed.1)
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::compile::Compiler;
-    use crate::execute::Machine;
+    use crate::asm;
+    use crate::compile::{Compiler, Division, Signedness, REMAINDER_REGISTER, RESULT_REGISTER};
+    use crate::debug::Debugger;
+    use crate::diagnostic::{Diagnostic, Span};
+    use crate::encoding::{self, DecodeError, ImageError};
+    use crate::execute::Instruction::{Add, AddI, Jump, JumpIf, Store, StoreI, Trap};
+    use crate::execute::RegisterContent;
+    use crate::execute::{
+        Instruction, Label, Machine, MachineError, Overflow, Register, RunOutcome, TrapKind,
+        DEFAULT_FUEL, DEFAULT_REGISTERS,
+    };
+    use crate::hex::{self, HexError};
+    use crate::link::{Labels, LinkError};
+    use crate::object::{DebugInfo, Object, ObjectError};
+    use crate::optimize;
     use crate::parsing::Parser::Expr;
-    use crate::tests::Op::{DivOp, MulOp};
+    use crate::regalloc::{self, MIN_REGISTERS};
+    use crate::tests::Op::{AddOp, DivOp, ModOp, MulOp, SubOp};
     use crate::tokens;
-    use std::fmt::{Display, Formatter};
+    use crate::tokens::Mode;
+    use crate::trace::{CollectingTracer, Event};
+    use crate::width::Width;
+    use crate::{compile_expression, load, machine, parse, Failure, Options};
+    use std::fmt::{Debug, Display, Formatter};
 
-    #[derive(Eq, PartialEq)]
+    #[allow(clippy::enum_variant_names)]
+    #[derive(Clone, Eq, PartialEq)]
     enum Op {
         MulOp,
         DivOp,
+        ModOp,
+        AddOp,
+        SubOp,
     }
 
     impl Op {
-        fn op(&self) -> fn(i16, i16) -> i16 {
+        fn precedence(&self) -> u8 {
             match self {
-                MulOp => |x, y| i16::overflowing_mul(x, y).0,
-                DivOp => |x, y| {
-                    if y == 0 && x != 0 {
-                        0x1F
-                    } else if y == 0 {
-                        0
-                    } else {
-                        i16::overflowing_div(x, y).0
-                    }
-                },
+                MulOp | DivOp | ModOp => 1,
+                AddOp | SubOp => 0,
+            }
+        }
+
+        /// `None` where the machine traps.
+        fn apply(&self, x: i32, y: i32) -> Option<i32> {
+            match self {
+                MulOp => Some(x * y),
+                DivOp => x.checked_div(y),
+                ModOp => x.checked_rem(y),
+                AddOp => Some(x + y),
+                SubOp => Some(x - y),
             }
         }
     }
@@ -70,6 +486,9 @@ mod tests {
             let rep = match self {
                 MulOp => "*",
                 DivOp => "/",
+                ModOp => "%",
+                AddOp => "+",
+                SubOp => "-",
             };
 
             write!(f, "{rep}")
@@ -77,51 +496,1269 @@ mod tests {
     }
 
     #[test]
-    fn testing() {
-        for op in [MulOp, DivOp] {
-            for i in 0..16 {
-                for j in 0..16 {
-                    println!("{i:04b}{op}{j:04b}");
+    fn diagnostics() {
+        let diagnostic = &unwrap_diagnostics(evaluate("0111/0010 0011", &Options::default()))[0];
+        assert_eq!(diagnostic.message, "Expected an operator");
+        assert_eq!(diagnostic.span, Span::new(10, 1));
+        assert_eq!(
+            diagnostic
+                .render("0111/0010 0011")
+                .lines()
+                .skip(1)
+                .collect::<Vec<_>>(),
+            ["  0111/0010 0011", "            ^"]
+        );
+    }
+
+    #[test]
+    fn missing_operand() {
+        let diagnostic = &unwrap_diagnostics(evaluate("0111/0010*)", &Options::default()))[0];
+        assert_eq!(diagnostic.span, Span::new(10, 1));
+        let diagnostic = &unwrap_diagnostics(evaluate("0111-", &Options::default()))[0];
+        assert_eq!(diagnostic.span, Span::new(5, 0));
+    }
+
+    #[test]
+    fn parentheses() {
+        assert_eq!(evaluate("0011*(0010+0001)", &Options::default()), Ok(9));
+        assert_eq!(evaluate("(0011+0001)/(0010)", &Options::default()), Ok(2));
+        assert_eq!(evaluate("((0011))-(0011-0001)", &Options::default()), Ok(1));
+
+        let spans = |input| {
+            unwrap_diagnostics(evaluate(input, &Options::default()))
+                .iter()
+                .map(|d| (d.message.clone(), d.span))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            spans("0011*(0010+0001"),
+            [("Unmatched '('".to_string(), Span::new(5, 1))]
+        );
+        assert_eq!(
+            spans("(0011 0010)"),
+            [("Expected ')'".to_string(), Span::new(6, 1))]
+        );
+        assert_eq!(
+            spans("0011+0001)*0010"),
+            [("Unmatched ')'".to_string(), Span::new(9, 1))]
+        );
+        assert_eq!(
+            spans("0011+()"),
+            [("Expected an operand".to_string(), Span::new(6, 1))]
+        );
+        for empty in ["", "   "] {
+            assert_eq!(
+                spans(empty),
+                [("Expected an operand".to_string(), Span::new(0, 0))]
+            );
+        }
+        assert_eq!(
+            spans("1 1"),
+            [("Expected an operator".to_string(), Span::new(2, 1))]
+        );
+        for input in ["*1", "-1", "+"] {
+            assert_eq!(
+                spans(input),
+                [("Expected an operand".to_string(), Span::new(0, 0))]
+            );
+        }
+        assert_eq!(
+            spans("  %1"),
+            [("Expected an operand".to_string(), Span::new(2, 0))]
+        );
+    }
+
+    #[test]
+    fn register_counts() {
+        let expression = "(0011*(0010+0001))/((0101-0011)*(0001+0001))-0001*(0011/0001)";
+        for registers in [MIN_REGISTERS, DEFAULT_REGISTERS, 8, 32] {
+            let options = Options {
+                registers,
+                ..Options::default()
+            };
+            assert_eq!(evaluate(expression, &options), Ok(-1));
+        }
+
+        // Past the last instruction a `Label` can reach, only once spilled with fewer registers
+        for (divisions, registers) in [(2600, DEFAULT_REGISTERS), (2000, MIN_REGISTERS)] {
+            let long = format!("{}1", "1/".repeat(divisions));
+            let options = Options {
+                registers,
+                ..Options::default()
+            };
+            let diagnostics = unwrap_diagnostics(evaluate(&long, &options));
+            assert_eq!(diagnostics[0].message, "Program too long");
+            assert_eq!(diagnostics[0].span, Span::new(0, long.len()));
+        }
+    }
+
+    #[test]
+    fn widths() {
+        let options = |bits| Options {
+            width: Width::new(bits).unwrap(),
+            ..Options::default()
+        };
+        assert_eq!(evaluate("11111111*00000010", &options(8)), Ok(510));
+        assert_eq!(
+            evaluate("111111111111/000000000011", &options(12)),
+            Ok(1365)
+        );
+        assert_eq!(evaluate("1*1+1", &options(1)), Ok(2));
+        assert_eq!(Width::new(8).unwrap().format_answer(-1), "1111111111111111");
+        assert_eq!(Width::default().format_answer(3), "00000011");
+    }
+
+    #[test]
+    fn literal_lengths() {
+        assert_eq!(evaluate("0111/10", &Options::default()), Ok(3));
+        assert_eq!(evaluate("1*11+(0)", &Options::default()), Ok(3));
+        assert_eq!(evaluate("0000000011*1", &Options::default()), Ok(3));
+
+        let diagnostic = &unwrap_diagnostics(evaluate("0011+10000", &Options::default()))[0];
+        assert_eq!(diagnostic.message, "Literal does not fit in 4 bits");
+        assert_eq!(diagnostic.span, Span::new(5, 5));
+    }
+
+    #[test]
+    fn signed_multiplication() {
+        for bits in [1, 4, 8, 15] {
+            let width = Width::new(bits).unwrap();
+            let options = Options {
+                width,
+                signedness: Signedness::Signed,
+                ..Options::default()
+            };
+            let signed = |x: i32| (x << (32 - bits)) >> (32 - bits);
+            for x in operands(width) {
+                for y in operands(width) {
+                    let input = format!("0b{x:b}*0b{y:b}");
                     assert_eq!(
-                        calculate(format!("{i:04b}{op}{j:04b}").as_str()),
-                        op.op()(i as i16, j as i16)
-                    )
+                        evaluate(&input, &options),
+                        Ok(signed(x) * signed(y)),
+                        "{input} with width {bits}"
+                    );
                 }
             }
         }
 
-        for op in [
-            (MulOp, MulOp),
-            (MulOp, DivOp),
-            (DivOp, DivOp),
-            (DivOp, MulOp),
-        ] {
-            for i in 0..16 {
-                for j in 0..16 {
-                    for k in 0..16 {
-                        if k == 0 && op.1 == DivOp {
-                            continue; //Whatever division by zero don't care..
+        let options = Options {
+            signedness: Signedness::Signed,
+            ..Options::default()
+        };
+        assert_eq!(evaluate("(0001-0011)*0011", &options), Ok(-6));
+        assert_eq!(
+            Width::default().format_answer(evaluate("1000*1000", &options).unwrap()),
+            "01000000"
+        );
+    }
+
+    #[test]
+    fn signed_addition() {
+        for bits in [1, 4, 8, 15] {
+            let width = Width::new(bits).unwrap();
+            let options = Options {
+                width,
+                signedness: Signedness::Signed,
+                ..Options::default()
+            };
+            let signed = |x: i32| (x << (32 - bits)) >> (32 - bits);
+            for x in operands(width) {
+                for y in operands(width) {
+                    let sum = format!("0b{x:b}+0b{y:b}");
+                    assert_eq!(evaluate(&sum, &options), Ok(signed(x) + signed(y)), "{sum}");
+                    let difference = format!("0b{x:b}-0b{y:b}");
+                    assert_eq!(
+                        evaluate(&difference, &options),
+                        Ok(signed(x) - signed(y)),
+                        "{difference} with width {bits}"
+                    );
+                }
+            }
+        }
+
+        let options = Options {
+            signedness: Signedness::Signed,
+            ..Options::default()
+        };
+        let answer = |input| Width::default().format_answer(evaluate(input, &options).unwrap());
+        assert_eq!(answer("1111-0001"), "11111110");
+        assert_eq!(answer("1111+0001"), "00000000");
+    }
+
+    #[test]
+    fn signed_division() {
+        for division in [Division::Truncated, Division::Floored, Division::Euclidean] {
+            for bits in [1, 4, 8, 15] {
+                let width = Width::new(bits).unwrap();
+                let options = Options {
+                    width,
+                    signedness: Signedness::Signed,
+                    division,
+                    ..Options::default()
+                };
+                let signed = |x: i32| (x << (32 - bits)) >> (32 - bits);
+                for x in operands(width) {
+                    for y in operands(width) {
+                        let input = format!("0b{x:b}/0b{y:b}");
+                        if y == 0 {
+                            assert!(
+                                matches!(
+                                    evaluate(&input, &options),
+                                    Err(Failure::Machine(MachineError::Trap { .. }))
+                                ),
+                                "{input} with width {bits} and {division:?} division"
+                            );
+                            continue;
                         }
-                        println!("{i:04b}{}{j:04b}{}{k:04b}", op.0, op.1);
+                        let (dividend, divisor) = (signed(x), signed(y));
+                        let expected = match division {
+                            Division::Truncated => dividend / divisor,
+                            Division::Floored => {
+                                let inexact = dividend % divisor != 0;
+                                dividend.div_euclid(divisor) - (divisor < 0 && inexact) as i32
+                            }
+                            Division::Euclidean => dividend.div_euclid(divisor),
+                        };
+                        assert_eq!(
+                            evaluate(&input, &options),
+                            Ok(expected),
+                            "{input} with width {bits} and {division:?} division"
+                        );
+                        let remainder = dividend - expected * divisor;
                         assert_eq!(
-                            calculate(format!("{i:04b}{}{j:04b}{}{k:04b}", op.0, op.1).as_str()),
-                            op.1.op()(op.0.op()(i as i16, j as i16) & 0xF, k as i16)
-                        )
+                            evaluate(&format!("0b{x:b}%0b{y:b}"), &options),
+                            Ok(remainder),
+                            "{input} with width {bits} and {division:?} division"
+                        );
+                        assert_eq!(
+                            evaluate_divmod(&input, &options),
+                            Ok((expected, remainder)),
+                            "{input} with width {bits} and {division:?} division"
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn divmod() {
+        for registers in MIN_REGISTERS..=DEFAULT_REGISTERS + 1 {
+            let options = Options {
+                registers,
+                ..Options::default()
+            };
+            assert_eq!(evaluate("1011%0011", &options), Ok(2));
+            assert_eq!(evaluate_divmod("1011/0011", &options), Ok((3, 2)));
+            assert_eq!(evaluate_divmod("1011%0011", &options), Ok((3, 2)));
+            assert_eq!(
+                evaluate_divmod("(0011*0011+0010)/(0001+0010)", &options),
+                Ok((3, 2))
+            );
+        }
+
+        // Results sitting in each other's registers have to be swapped through a third
+        let program = vec![
+            StoreI {
+                constant: 1,
+                register: 0,
+            },
+            StoreI {
+                constant: 2,
+                register: 1,
+            },
+            StoreI {
+                constant: 3,
+                register: 2,
+            },
+            StoreI {
+                constant: 4,
+                register: 3,
+            },
+            Add {
+                lhs: 0,
+                rhs: 1,
+                dest: 0,
+            },
+        ];
+        let results = [(2, RESULT_REGISTER), (3, REMAINDER_REGISTER)];
+        let mut machine = Machine::with_registers(MIN_REGISTERS);
+        machine
+            .run(
+                regalloc::allocate(program, &results, MIN_REGISTERS)
+                    .unwrap()
+                    .0,
+            )
+            .unwrap();
+        assert_eq!(machine.answer_by_convention(), 3);
+        assert_eq!(machine.remainder_by_convention(), 4);
+
+        let diagnostic = &unwrap_diagnostics(evaluate_divmod("0011*0011", &Options::default()))[0];
+        assert_eq!(diagnostic.message, "Expected a division");
+        assert_eq!(diagnostic.span, Span::new(0, 9));
+    }
+
+    #[test]
+    fn division_by_zero() {
+        for input in ["0011/(0001-0001)", "0000%0000", "0011*0011/0"] {
+            let options = Options::default();
+            let compiled = Compiler::new(
+                parse(input, &options).unwrap(),
+                options.width,
+                options.signedness,
+                options.division,
+            )
+            .compile(options.registers)
+            .unwrap();
+            let trap = compiled
+                .program
+                .iter()
+                .position(|instruction| matches!(instruction, Trap { .. }))
+                .unwrap();
+
+            let error = MachineError::Trap {
+                kind: TrapKind::DivisionByZero,
+                instruction: trap as Label,
+            };
+            assert_eq!(
+                error.to_string(),
+                format!("Division by zero trapped at instruction {trap}")
+            );
+            assert_eq!(evaluate(input, &options), Err(Failure::Machine(error)));
+        }
+    }
+
+    #[test]
+    fn machine_errors() {
+        let run = |program, overflow| Machine::default().overflow(overflow).run(program);
+        assert_eq!(run(vec![], Overflow::Wrap), Ok(RunOutcome { steps: 0 }));
+        assert_eq!(
+            run(vec![Jump { instruction: 1 }], Overflow::Wrap),
+            Ok(RunOutcome { steps: 1 })
+        );
+        assert_eq!(
+            run(vec![Jump { instruction: 5 }], Overflow::Wrap),
+            Err(MachineError::PcOutOfRange { pc: 5 })
+        );
+        // Running on from the last instruction a `Label` can count to
+        let negate = Instruction::Negate { register: 0 };
+        assert_eq!(
+            run(vec![negate; Label::MAX as usize + 1], Overflow::Wrap),
+            Err(MachineError::PcOutOfRange { pc: Label::MAX })
+        );
+        assert_eq!(
+            run(
+                vec![StoreI {
+                    constant: 1,
+                    register: DEFAULT_REGISTERS,
+                }],
+                Overflow::Wrap
+            ),
+            Err(MachineError::InvalidRegister {
+                register: DEFAULT_REGISTERS,
+                instruction: 0,
+            })
+        );
+        assert_eq!(
+            run(vec![Jump { instruction: 0 }], Overflow::Wrap),
+            Err(MachineError::StepLimitExceeded {
+                limit: DEFAULT_FUEL
+            })
+        );
+
+        let overflowing = || {
+            vec![
+                StoreI {
+                    constant: i32::MAX,
+                    register: 3,
+                },
+                AddI {
+                    register: 3,
+                    constant: 1,
+                },
+            ]
+        };
+        assert_eq!(
+            run(overflowing(), Overflow::Trap),
+            Err(MachineError::Overflow { instruction: 1 })
+        );
+        let mut machine = Machine::default();
+        assert_eq!(machine.run(overflowing()), Ok(RunOutcome { steps: 2 }));
+        assert_eq!(machine.answer_by_convention(), i32::MIN);
+
+        // Compiled code never overflows
+        for signedness in [Signedness::Unsigned, Signedness::Signed] {
+            let options = Options {
+                width: Width::new(Width::MAX).unwrap(),
+                signedness,
+                overflow: Overflow::Trap,
+                ..Options::default()
+            };
+            for input in ["0x7fff*0x7fff", "0x7fff/1", "0x4000/0x7fff", "0-0x7fff"] {
+                assert!(evaluate(input, &options).is_ok(), "{input}");
+            }
+        }
+    }
+
+    #[test]
+    fn fuel() {
+        let options = Options::default();
+        let (mut machine, program) = prepare("0111*0011", &options, false).unwrap();
+        let steps = machine.run(program.clone()).unwrap().steps;
+
+        let (machine, _) = prepare("0111*0011", &options, false).unwrap();
+        let mut machine = machine.fuel(steps / 2);
+        assert_eq!(
+            machine.run(program.clone()),
+            Err(MachineError::StepLimitExceeded { limit: steps / 2 })
+        );
+        assert_eq!(
+            machine.resume(1),
+            Err(MachineError::StepLimitExceeded {
+                limit: steps / 2 + 1
+            })
+        );
+        assert_eq!(machine.resume(steps), Ok(RunOutcome { steps }));
+        assert_eq!(machine.answer_by_convention(), 21);
+
+        let mut machine = Machine::default().fuel(steps);
+        assert_eq!(machine.run(program), Ok(RunOutcome { steps }));
+    }
+
+    #[test]
+    fn tracing() {
+        let (machine, program) = prepare("0111*0011", &Options::default(), false).unwrap();
+        let mut collected = CollectingTracer::default();
+        let mut machine = machine.tracer(&mut collected);
+        let steps = machine.run(program.clone()).unwrap().steps;
+        assert_eq!(machine.answer_by_convention(), 21);
+
+        let events = collected.events;
+        assert_eq!(events.last(), Some(&Event::Halt { steps }));
+        let mut pc = 0;
+        let mut ran = 0;
+        let mut rest = &events[..events.len() - 1];
+        while let [Event::Before {
+            pc: before,
+            instruction,
+        }, tail @ ..] = rest
+        {
+            assert_eq!((*before, instruction), (pc, &program[pc as usize]));
+            let tail = match tail {
+                [Event::Jump { from, to }, tail @ ..] => {
+                    assert_eq!((*from, instruction.target()), (pc, Some(*to)));
+                    pc = *to;
+                    tail
+                }
+                _ => {
+                    pc += 1;
+                    tail
+                }
+            };
+            let [Event::After { pc: after, .. }, tail @ ..] = tail else {
+                panic!("Expected an after event for instruction {before}");
+            };
+            assert_eq!(after, before);
+            ran += 1;
+            rest = tail;
+        }
+        assert!(rest.is_empty());
+        assert_eq!(ran, steps);
+        assert_eq!(pc as usize, program.len());
+    }
+
+    /// One of every kind of instruction.
+    fn every_instruction() -> Vec<Instruction> {
+        use crate::execute::Instruction::*;
+        vec![
+            Negate { register: 1 },
+            AndI {
+                register: 4,
+                constant: 15,
+            },
+            Jump { instruction: 7 },
+            JumpIf {
+                instruction: 7,
+                test: 0,
+            },
+            StoreI {
+                constant: -3,
+                register: 2,
+            },
+            Copy { src: 1, dest: 3 },
+            Subtract {
+                lhs: 0,
+                rhs: 1,
+                dest: 2,
+            },
+            SubtractI {
+                register: 3,
+                constant: 1,
+            },
+            Add {
+                lhs: 3,
+                rhs: 4,
+                dest: 0,
+            },
+            AddI {
+                register: 4,
+                constant: 15,
+            },
+            ShiftLeft {
+                register: 0,
+                amount: 1,
+            },
+            ShiftRight {
+                register: 1,
+                amount: 2,
+            },
+            ShiftRightArithmetic {
+                register: 2,
+                amount: 31,
+            },
+            Store {
+                register: 0,
+                slot: 3,
+            },
+            Load {
+                slot: 3,
+                register: 1,
+            },
+            Trap {
+                kind: TrapKind::DivisionByZero,
+            },
+        ]
+    }
+
+    #[test]
+    fn disassembly() {
+        let program = every_instruction();
+        let disassembly: Vec<String> = program.iter().map(ToString::to_string).collect();
+        assert_eq!(
+            disassembly,
+            [
+                "negate r1",
+                "andi r4, 15",
+                "jump @7",
+                "jumpif r0, @7",
+                "storei r2, -3",
+                "copy r3, r1",
+                "subtract r2, r0, r1",
+                "subtracti r3, 1",
+                "add r0, r3, r4",
+                "addi r4, 15",
+                "shiftleft r0, 1",
+                "shiftright r1, 2",
+                "shiftrighta r2, 31",
+                "store [3], r0",
+                "load r1, [3]",
+                "trap divbyzero",
+            ]
+        );
+    }
+
+    #[test]
+    fn encodings() {
+        let program = every_instruction();
+        for instruction in &program {
+            assert_eq!(
+                encoding::decode(encoding::encode(instruction)),
+                Ok(instruction.clone())
+            );
+        }
+        let (_, compiled) = prepare("0111*0011/0010%0011", &Options::default(), false).unwrap();
+        for program in [program, compiled] {
+            let image = encoding::encode_program(&program);
+            assert_eq!(image.len(), program.len() * encoding::INSTRUCTION_BYTES);
+            assert_eq!(encoding::decode_program(&image), Ok(program));
+        }
+
+        assert_eq!(
+            encoding::encode(&AddI {
+                register: 4,
+                constant: -2
+            }),
+            [9, 4, 0, 0, 0xfe, 0xff, 0xff, 0xff]
+        );
+        for (bytes, error) in [
+            ([16, 0, 0, 0, 0, 0, 0, 0], DecodeError::UnknownOpcode(16)),
+            ([15, 1, 0, 0, 0, 0, 0, 0], DecodeError::UnknownTrap(1)),
+            // Unused operands have to be zero
+            ([0, 1, 2, 0, 0, 0, 0, 0], DecodeError::InvalidOperand),
+            ([2, 0, 0, 0, 0, 0, 1, 0], DecodeError::InvalidOperand),
+            (
+                [13, 0, 0, 0, 0xff, 0xff, 0xff, 0xff],
+                DecodeError::InvalidOperand,
+            ),
+        ] {
+            assert_eq!(encoding::decode(bytes), Err(error));
+        }
+        let image = [0, 1, 0, 0, 0, 0, 0, 0, 5, 1];
+        assert_eq!(
+            encoding::decode_program(&image),
+            Err(ImageError::Truncated { len: 10 })
+        );
+        let long = encoding::encode_program(&[Trap {
+            kind: TrapKind::DivisionByZero,
+        }])
+        .repeat(Label::MAX as usize + 2);
+        assert_eq!(
+            encoding::decode_program(&long),
+            Err(ImageError::TooLong {
+                instructions: Label::MAX as usize + 2
+            })
+        );
+        assert_eq!(
+            encoding::decode_program(&long[encoding::INSTRUCTION_BYTES..]).map(|p| p.len()),
+            Ok(Label::MAX as usize + 1)
+        );
+        let mut image = image[..8].repeat(2);
+        image.extend([99; 8]);
+        assert_eq!(
+            encoding::decode_program(&image),
+            Err(ImageError::Instruction {
+                index: 2,
+                error: DecodeError::UnknownOpcode(99)
+            })
+        );
+    }
+
+    #[test]
+    fn object_files() {
+        let source = "0011+(0011/(0001-0001))";
+        let options = Options {
+            registers: 6,
+            width: Width::new(12).unwrap(),
+            ..Options::default()
+        };
+        let compiled = compile_expression(source, &options, false).unwrap();
+        let mut object = Object {
+            width: options.width,
+            registers: options.registers,
+            program: compiled.program,
+            debug: Some(DebugInfo {
+                source: source.to_owned(),
+                spans: compiled.spans,
+            }),
+        };
+        let bytes = object.to_bytes();
+        assert_eq!(Object::from_bytes(&bytes).as_ref(), Ok(&object));
+
+        let mut loaded = Options::default();
+        let (program, debug) = load(&bytes, &mut loaded).unwrap();
+        assert_eq!((loaded.width, loaded.registers), (options.width, 6));
+        let debug = debug.unwrap();
+        let error = machine(&loaded).run(program).unwrap_err();
+        let span = debug.span(error.instruction().unwrap()).unwrap();
+        assert_eq!(&source[span.offset..][..span.len], "0011/(0001-0001");
+
+        let debug = object.debug.take();
+        let stripped = object.to_bytes();
+        assert!(stripped.len() < bytes.len());
+        assert_eq!(Object::from_bytes(&stripped), Ok(object.clone()));
+        object.debug = debug;
+
+        // A raw image loads too, without any debug section
+        let image = encoding::encode_program(&object.program);
+        assert_eq!(
+            load(&image, &mut Options::default()),
+            Ok((object.program.clone(), None))
+        );
+
+        let header = |f: fn(&mut Vec<u8>)| {
+            let mut bytes = bytes.clone();
+            f(&mut bytes);
+            Object::from_bytes(&bytes)
+        };
+        assert_eq!(header(|b| b[0] = b'X'), Err(ObjectError::NotAnObject));
+        assert_eq!(
+            header(|b| b[4] = 2),
+            Err(ObjectError::UnsupportedVersion(2))
+        );
+        assert_eq!(header(|b| b[6] = 0), Err(ObjectError::InvalidWidth(0)));
+        assert_eq!(header(|b| b[7] = 3), Err(ObjectError::TooFewRegisters(3)));
+        assert_eq!(header(|b| b.truncate(20)), Err(ObjectError::Truncated));
+        assert_eq!(header(|b| b.push(0)), Err(ObjectError::TrailingBytes));
+        assert_eq!(
+            header(|b| b[12] = 99),
+            Err(ObjectError::Instructions(ImageError::Instruction {
+                index: 0,
+                error: DecodeError::UnknownOpcode(99)
+            }))
+        );
+        // The last span points past the end of the source
+        assert_eq!(
+            header(|b| {
+                let len = b.len();
+                b[len - 8] = 0xff;
+                b[len - 7] = 0;
+            }),
+            Err(ObjectError::InvalidDebugInfo)
+        );
+    }
+
+    #[test]
+    fn intel_hex() {
+        let (_, compiled) = prepare("0111*0011/0010%0011", &Options::default(), false).unwrap();
+        for program in [every_instruction(), compiled, vec![]] {
+            let hex = hex::encode(&program);
+            assert_eq!(hex::decode(&hex), Ok(program.clone()));
+            assert_eq!(
+                load(hex.as_bytes(), &mut Options::default()),
+                Ok((program, None))
+            );
+        }
+
+        let program = [
+            StoreI {
+                constant: 7,
+                register: 0,
+            },
+            AddI {
+                register: 0,
+                constant: -2,
+            },
+            Trap {
+                kind: TrapKind::DivisionByZero,
+            },
+        ];
+        let hex = ":10000000040000000700000009000000FEFFFFFFE1\n\
+                   :080010000F00000000000000D9\n\
+                   :00000001FF\n";
+        assert_eq!(hex::encode(&program), hex);
+        // Blank lines and Windows line endings are fine
+        assert_eq!(
+            hex::decode(&format!("\r\n{}", hex.replace('\n', "\r\n"))),
+            Ok(program.to_vec())
+        );
+
+        // Past 64 KiB the upper half of the address goes in its own record
+        let long = vec![
+            Trap {
+                kind: TrapKind::DivisionByZero
+            };
+            0x2001
+        ];
+        let hex = hex::encode(&long);
+        assert!(hex.contains("\n:020000040001F9\n:080000000F00000000000000E9\n"));
+        assert_eq!(hex::decode(&hex), Ok(long));
+
+        for (hex, error) in [
+            ("10000000", HexError::Malformed { line: 1 }),
+            (":0000000", HexError::Malformed { line: 1 }),
+            (":0000+001FF", HexError::Malformed { line: 1 }),
+            // Says two bytes of data but has none
+            (":02000000FE", HexError::Malformed { line: 1 }),
+            // An end of file record with data
+            (":0100000100FE", HexError::Malformed { line: 1 }),
+            (
+                ":0000000100",
+                HexError::Checksum {
+                    line: 1,
+                    expected: 0xff,
+                    found: 0,
+                },
+            ),
+            (
+                ":020000021000EC\n:00000001FF",
+                HexError::UnsupportedRecord { line: 1, kind: 2 },
+            ),
+            (
+                ":080008000F00000000000000E1\n:00000001FF",
+                HexError::Address {
+                    line: 1,
+                    expected: 0,
+                    found: 8,
+                },
+            ),
+            (
+                ":080000000F00000000000000E9\n:080000000F00000000000000E9\n:00000001FF",
+                HexError::Address {
+                    line: 2,
+                    expected: 8,
+                    found: 0,
+                },
+            ),
+            (":080000000F00000000000000E9", HexError::MissingEnd),
+            (":00000001FF\n:00000001FF", HexError::AfterEnd { line: 2 }),
+            (
+                ":08000000630000000000000095\n:00000001FF",
+                HexError::Image(ImageError::Instruction {
+                    index: 0,
+                    error: DecodeError::UnknownOpcode(0x63),
+                }),
+            ),
+            (
+                ":040000000F000000ED\n:00000001FF",
+                HexError::Image(ImageError::Truncated { len: 4 }),
+            ),
+        ] {
+            assert_eq!(hex::decode(hex), Err(error), "{hex}");
+        }
+    }
+
+    #[test]
+    fn peephole() {
+        let program = asm::assemble(
+            "
+            storei r0, 7
+            andi r0, 0
+            storei r0, 0
+            copy r3, r1
+            copy r1, r3
+            store [2], r1
+            load r1, [2]
+            jumpif r0, back
+            storei r2, -1
+    back:   andi r2, 15
+            jump @14
+            ",
+        )
+        .unwrap();
+        let (optimized, origins) = optimize::optimize(program);
+        assert_eq!(
+            optimized,
+            asm::assemble(
+                "
+                storei r0, 0
+                copy r3, r1
+                store [2], r1
+                jumpif r0, back
+                storei r2, -1
+        back:   andi r2, 15
+                jump @10
+                "
+            )
+            .unwrap()
+        );
+        assert_eq!(origins, [0, 3, 5, 7, 8, 9, 10]);
+
+        // A jump to the first of a pair goes to what replaces it
+        let program =
+            asm::assemble("jump mask\nmask: storei r0, 18\nandi r0, 15\nandi r1, 0\nstorei r1, 0")
+                .unwrap();
+        assert_eq!(
+            optimize::optimize(program).0,
+            asm::assemble("jump @1\nstorei r0, 2\nstorei r1, 0").unwrap()
+        );
+
+        let expressions = [
+            "0111*0011+0001",
+            "1101/0011",
+            "(0001+0010)*(0011+0100)-0110",
+        ];
+        let mut saved = 0;
+        for expression in expressions {
+            for registers in [4, 5, DEFAULT_REGISTERS] {
+                for signedness in [Signedness::Unsigned, Signedness::Signed] {
+                    let options = Options {
+                        registers,
+                        signedness,
+                        ..Options::default()
+                    };
+                    let (mut machine, program) = prepare(expression, &options, false).unwrap();
+                    let (optimized, _) = optimize::optimize(program.clone());
+                    machine.run(program.clone()).unwrap();
+                    let (answer, steps) = (machine.answer_by_convention(), machine.steps());
+                    let mut machine = crate::machine(&options);
+                    machine.run(optimized.clone()).unwrap();
+                    assert_eq!(machine.answer_by_convention(), answer, "{expression}");
+                    assert!(machine.steps() <= steps);
+                    saved += program.len() - optimized.len();
+                }
+            }
+        }
+        assert!(saved > 0);
+    }
+
+    #[test]
+    fn linking() {
+        let mut labels = Labels::default();
+        let (top, end, nowhere) = (labels.label(), labels.label(), labels.label());
+        labels.define(top, 0);
+        labels.define(end, 3);
+        let program = vec![
+            JumpIf {
+                instruction: end,
+                test: 0,
+            },
+            AddI {
+                register: 0,
+                constant: 1,
+            },
+            Jump { instruction: top },
+        ];
+        assert_eq!(
+            labels.link(program.clone()),
+            Ok(vec![
+                JumpIf {
+                    instruction: 3,
+                    test: 0,
+                },
+                AddI {
+                    register: 0,
+                    constant: 1,
+                },
+                Jump { instruction: 0 },
+            ])
+        );
+
+        labels.define(top, 2);
+        let mut program = program;
+        program.push(Jump {
+            instruction: nowhere,
+        });
+        program.push(Jump {
+            instruction: nowhere,
+        });
+        assert_eq!(
+            labels.link(program),
+            Err(vec![
+                LinkError::DefinedTwice { label: top },
+                LinkError::Undefined { label: nowhere },
+            ])
+        );
+
+        // Labels and instruction indices have to fit in a `Label`
+        let mut labels = Labels::default();
+        let far = labels.label();
+        labels.define(far, Label::MAX as usize + 1);
+        for _ in 0..=Label::MAX {
+            labels.label();
+        }
+        assert_eq!(
+            labels.link::<Register>(vec![Jump { instruction: far }]),
+            Err(vec![
+                LinkError::OutOfRange {
+                    label: far,
+                    at: Label::MAX as usize + 1
+                },
+                LinkError::TooManyLabels,
+            ])
+        );
+        let source = format!(
+            "{}end: jump end",
+            "negate r0\n".repeat(Label::MAX as usize + 1)
+        );
+        let errors = asm::assemble(&source).unwrap_err();
+        assert_eq!(
+            errors[0].message,
+            "Label end is past the last instruction a jump can reach"
+        );
+    }
+
+    #[test]
+    fn assembler() {
+        let expression = "(0011*(0010+0001))/((0101-0011)*(0001+0001))-0001*(0011/0001)";
+        for registers in [MIN_REGISTERS, DEFAULT_REGISTERS] {
+            for signedness in [Signedness::Unsigned, Signedness::Signed] {
+                let options = Options {
+                    registers,
+                    signedness,
+                    ..Options::default()
+                };
+                let (_, program) = prepare(expression, &options, false).unwrap();
+                let disassembly: String = program.iter().map(|i| format!("{i}\n")).collect();
+                assert_eq!(asm::assemble(&disassembly), Ok(program));
+            }
+        }
+
+        let source = include_str!("../programs/multiply.asm");
+        let mut machine = Machine::default();
+        machine.run(asm::assemble(source).unwrap()).unwrap();
+        assert_eq!(machine.answer_by_convention(), 21);
+
+        let program = asm::assemble("start: jump end ; done\n\nloop: jumpif r1, loop\nend:");
+        assert_eq!(
+            program,
+            Ok(vec![
+                Jump { instruction: 2 },
+                JumpIf {
+                    instruction: 1,
+                    test: 1
+                }
+            ])
+        );
+
+        let source = "negate r1 r2\nx: addi r1,\n jump y\nx: trap 0\n";
+        let rendered: Vec<String> = asm::assemble(source)
+            .unwrap_err()
+            .iter()
+            .map(|diagnostic| diagnostic.render(source))
+            .collect();
+        assert_eq!(
+            rendered,
+            [
+                "1:8: Expected a register like r3\n  negate r1 r2\n         ^^^^^",
+                "2:12: Expected a number\n  x: addi r1,\n             ^",
+                "3:7: Label y is not defined\n   jump y\n        ^",
+                "4:1: Label x is defined twice\n  x: trap 0\n  ^",
+                "4:9: Expected a kind of trap like divbyzero\n  x: trap 0\n          ^",
+            ]
+        );
+    }
+
+    #[test]
+    fn time_travel() {
+        // Four registers spill, so memory has to be rewound as well
+        let options = Options {
+            registers: MIN_REGISTERS,
+            ..Options::default()
+        };
+        let expression = "(0011*(0010+0001))/((0101-0011)*(0001+0001))-0001*(0011/0001)";
+        let (machine, program) = prepare(expression, &options, false).unwrap();
+        assert!(program
+            .iter()
+            .any(|instruction| matches!(instruction, Store { .. })));
+        let mut collected = CollectingTracer::default();
+        machine.tracer(&mut collected).run(program.clone()).unwrap();
+        let snapshots: Vec<Vec<RegisterContent>> = collected
+            .events
+            .into_iter()
+            .filter_map(|event| match event {
+                Event::After { registers, .. } => Some(registers),
+                _ => None,
+            })
+            .collect();
+
+        let (machine, _) = prepare(expression, &options, false).unwrap();
+        let mut machine = machine.record();
+        let steps = machine.run(program.clone()).unwrap().steps;
+        assert_eq!(snapshots.len() as u64, steps);
+        for register in 0..MIN_REGISTERS {
+            let history = machine.register_history(register).unwrap();
+            assert_eq!(history[0], 0);
+            let expected: Vec<_> = snapshots.iter().map(|s| s[register as usize]).collect();
+            assert_eq!(history[1..], expected);
+        }
+        assert_eq!(machine.register_history(MIN_REGISTERS), None);
+
+        assert!(!machine.rewind(steps + 1));
+        assert!(machine.rewind(steps / 2));
+        assert_eq!(machine.steps(), steps / 2);
+        assert_eq!(machine.registers(), snapshots[steps as usize / 2 - 1]);
+        assert_eq!(machine.resume(0), Ok(RunOutcome { steps }));
+        assert_eq!(machine.answer_by_convention(), -1);
+
+        assert!(machine.rewind(0));
+        assert_eq!((machine.pc(), machine.registers()), (0, &[0; 4][..]));
+        assert!(!machine.step_back());
+        assert_eq!(machine.resume(0), Ok(RunOutcome { steps }));
+        assert_eq!(machine.answer_by_convention(), -1);
+
+        let mut machine = Machine::default();
+        machine.run(program).unwrap();
+        assert!(!machine.step_back());
+        assert!(!machine.rewind(0));
+        assert_eq!(machine.register_history(0), None);
+    }
+
+    #[test]
+    fn debugger() {
+        let (machine, program) = prepare("0111*0011", &Options::default(), false).unwrap();
+        let mut debugger = Debugger::new(machine, program);
+        let script =
+            "b 8\nc\nset r1 0b0\nd 8\nb 99\nfrobnicate\ns 2\nc\nr\nback 99\nback 3\nq\nr\n";
+        let mut output = Vec::new();
+        debugger.repl(script.as_bytes(), &mut output).unwrap();
+
+        let output = String::from_utf8(output).unwrap();
+        for expected in [
+            "Breakpoint at 8\n",
+            "=>*   8  andi r4, 1\n",
+            "r1 = 0 (00000000)\n",
+            "Deleted the breakpoint at 8\n",
+            "There is no instruction 99\n",
+            "Unknown command \"frobnicate\", try help\n",
+            "=>   10  add r2, r0, r2\n",
+            "Halted after 40 steps\n",
+            // Only the multiplier's lowest bit was used before clearing it
+            "r3 = 7 (00000111)\n",
+            "Only 40 steps to go back\n",
+            "Back to 37 steps\n",
+        ] {
+            assert!(output.contains(expected), "{expected:?} in {output}");
+        }
+        // Nothing runs after quit
+        assert!(output.ends_with("(debug) "));
+    }
+
+    #[test]
+    fn prefixes() {
+        assert_eq!(evaluate("0x7/0b10*3", &Options::default()), Ok(9));
+        assert_eq!(evaluate("0o17-0d12+0XA", &Options::default()), Ok(13));
+        assert_eq!(evaluate("0x0b*0B1", &Options::default()), Ok(11));
+        assert_eq!(evaluate("10*2", &Options::default()), Ok(4));
+
+        let diagnostic = |input| {
+            let diagnostic = &unwrap_diagnostics(evaluate(input, &Options::default()))[0];
+            (diagnostic.message.clone(), diagnostic.span)
+        };
+        assert_eq!(
+            diagnostic("0b12"),
+            ("Not a base 2 digit".to_string(), Span::new(3, 1))
+        );
+        assert_eq!(
+            diagnostic("0x10"),
+            (
+                "Literal does not fit in 4 bits".to_string(),
+                Span::new(0, 4)
+            )
+        );
+        assert_eq!(
+            diagnostic("0112*0010"),
+            (
+                "Decimal literals starting with 0 need a 0d prefix".to_string(),
+                Span::new(0, 4)
+            )
+        );
+        assert_eq!(
+            diagnostic("0x 7"),
+            ("Expected digits".to_string(), Span::new(0, 2))
+        );
+        // Letters are only digits in a hex literal
+        assert_eq!(evaluate("0xa+0xF", &Options::default()), Ok(25));
+        assert_eq!(
+            diagnostic("0111a0010"),
+            ("Unexpected character 'a'".to_string(), Span::new(4, 1))
+        );
+        assert_eq!(
+            diagnostic("0x1+f"),
+            ("Unexpected character 'f'".to_string(), Span::new(4, 1))
+        );
+    }
+
+    #[test]
+    fn tokenizer_modes() {
+        let errors = unwrap_diagnostics(evaluate("0111*#0010?", &Options::default()));
+        let spans: Vec<_> = errors.iter().map(|d| d.span).collect();
+        assert_eq!(spans, [Span::new(5, 1), Span::new(10, 1)]);
+
+        assert_eq!(evaluate("0111 * 0010", &Options::default()), Ok(14));
+        assert_eq!(
+            evaluate(
+                "0111/x0010",
+                &Options {
+                    mode: Mode::Lenient,
+                    ..Options::default()
+                }
+            ),
+            Ok(3)
+        );
+    }
+
+    #[test]
+    fn testing() {
+        for width in [1, 4, 8, 12, Width::MAX].map(|bits| Width::new(bits).unwrap()) {
+            let bin = |x: i32| format!("{x:0w$b}", w = width.bits() as usize);
+            let mask = width.mask();
+            let operands = operands(width);
+
+            for op in [MulOp, DivOp, ModOp, AddOp, SubOp] {
+                for &i in &operands {
+                    for &j in &operands {
+                        let inp = format!("{}{op}{}", bin(i), bin(j));
+                        println!("{inp}");
+                        assert_eq!(calculate(&inp, width), op.apply(i, j))
                     }
                 }
             }
+
+            let ops = [MulOp, DivOp, ModOp, AddOp, SubOp];
+            let pairs = ops
+                .clone()
+                .into_iter()
+                .flat_map(|x| ops.clone().map(|y| (x.clone(), y)));
+            for op in pairs {
+                for &i in &operands {
+                    for &j in &operands {
+                        for &k in &operands {
+                            let inp = format!("{}{}{}{}{}", bin(i), op.0, bin(j), op.1, bin(k));
+                            println!("{inp}");
+                            let grouped_right =
+                                op.1.apply(j, k).and_then(|x| op.0.apply(i, x & mask));
+                            let expected = if op.1.precedence() > op.0.precedence() {
+                                grouped_right
+                            } else {
+                                op.0.apply(i, j).and_then(|x| op.1.apply(x & mask, k))
+                            };
+                            assert_eq!(calculate(&inp, width), expected);
+
+                            let inp = format!("{}{}({}{}{})", bin(i), op.0, bin(j), op.1, bin(k));
+                            assert_eq!(calculate(&inp, width), grouped_right);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Every operand for narrow widths, the edges and a few in between for wider ones.
+    fn operands(width: Width) -> Vec<i32> {
+        let mask = width.mask();
+        if width.bits() <= 4 {
+            return (0..=mask).collect();
         }
+        vec![
+            0,
+            1,
+            2,
+            0b101 & mask,
+            mask / 3,
+            mask / 2 + 1,
+            mask - 1,
+            mask,
+        ]
     }
 
-    fn calculate(inp: &str) -> i16 {
-        let tokens = tokens::tokenize(inp).unwrap();
-        let parsed = Expr.parse(tokens, &[]).unwrap();
+    /// A machine built to `options` and the program for `input` to run on it, a single division
+    /// for `divmod`.
+    fn prepare(
+        input: &str,
+        options: &Options,
+        divmod: bool,
+    ) -> Result<(Machine, Vec<Instruction>), Failure> {
+        let compiled = compile_expression(input, options, divmod)?;
+        Ok((machine(options), compiled.program))
+    }
+
+    fn evaluate(input: &str, options: &Options) -> Result<RegisterContent, Failure> {
+        let (mut machine, program) = prepare(input, options, false)?;
+        machine.run(program)?;
+        Ok(machine.answer_by_convention())
+    }
+
+    /// Quotient and remainder of the single division `input` is.
+    fn evaluate_divmod(
+        input: &str,
+        options: &Options,
+    ) -> Result<(RegisterContent, RegisterContent), Failure> {
+        let (mut machine, program) = prepare(input, options, true)?;
+        machine.run(program)?;
+        Ok((
+            machine.answer_by_convention(),
+            machine.remainder_by_convention(),
+        ))
+    }
+
+    /// `None` where the machine traps.
+    fn calculate(inp: &str, width: Width) -> Option<i32> {
+        let tokens = tokens::tokenize(inp, Mode::Strict).unwrap();
+        let parsed = Expr.parse(tokens, &[], width).unwrap();
         let nodes = parsed.1;
 
-        let compiled = Compiler::new(nodes).compile().unwrap();
+        let mut machine = Machine::default();
+        let compiled = Compiler::new(nodes, width, Signedness::Unsigned, Division::Truncated)
+            .compile(DEFAULT_REGISTERS)
+            .unwrap();
+        machine.run(compiled.program).ok()?;
+        Some(machine.answer_by_convention())
+    }
 
-        let mut machine = Machine::new();
-        machine.run(compiled);
-        machine.answer_by_convention()
+    fn unwrap_diagnostics<T: Debug>(result: Result<T, Failure>) -> Vec<Diagnostic> {
+        match result {
+            Err(Failure::Diagnostics(diagnostics)) => diagnostics,
+            other => panic!("Expected diagnostics, got {other:?}"),
+        }
     }
 }
@@ -0,0 +1,196 @@
+//! Object files for compiled programs, `.bcb` by convention.
+//!
+//! All numbers are little endian:
+//!
+//! | bytes        | contents                                                    |
+//! |--------------|-------------------------------------------------------------|
+//! | 4            | `MAGIC`                                                     |
+//! | 2            | format version, `VERSION`                                   |
+//! | 1            | width of the operands in bits                               |
+//! | 1            | registers the program was compiled for                      |
+//! | 4            | number of instructions                                      |
+//! | 8 each       | the instructions, encoded as in `encoding`                  |
+//! | 1            | 1 if a debug section follows, 0 if not                      |
+//!
+//! The debug section is the source the program was compiled from, its length in 4 bytes before
+//! it, then the span of the source each instruction came from as a 4 byte offset and a 4 byte
+//! length. An instruction that does not come from anywhere in particular has offset `u32::MAX`.
+
+use crate::diagnostic::Span;
+use crate::encoding::{self, ImageError, INSTRUCTION_BYTES};
+use crate::execute::{Instruction, Label};
+use crate::regalloc::MIN_REGISTERS;
+use crate::width::Width;
+use std::fmt::{Display, Formatter};
+
+pub const MAGIC: [u8; 4] = *b"BCB\0";
+/// The format version written, and the only one read.
+pub const VERSION: u16 = 1;
+
+/// Offset of an instruction without a span in the debug section.
+const NO_SPAN: u32 = u32::MAX;
+
+/// A compiled program and what running it needs to know.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Object {
+    pub width: Width,
+    /// Registers the `Machine` running the program needs.
+    pub registers: u8,
+    pub program: Vec<Instruction>,
+    pub debug: Option<DebugInfo>,
+}
+
+/// Where the instructions of an `Object` came from.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct DebugInfo {
+    pub source: String,
+    /// For each instruction, the span of `source` it was compiled from if there is one.
+    pub spans: Vec<Option<Span>>,
+}
+
+impl DebugInfo {
+    /// The span of `source` the instruction at `instruction` was compiled from.
+    pub fn span(&self, instruction: Label) -> Option<Span> {
+        self.spans.get(instruction as usize).copied().flatten()
+    }
+}
+
+/// Why bytes are not an object file.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum ObjectError {
+    NotAnObject,
+    UnsupportedVersion(u16),
+    /// The file ends before everything the header promises.
+    Truncated,
+    /// There is more after the end of the object.
+    TrailingBytes,
+    InvalidWidth(u8),
+    TooFewRegisters(u8),
+    Instructions(ImageError),
+    /// The debug section is not UTF-8, or has spans outside the source.
+    InvalidDebugInfo,
+}
+
+impl Display for ObjectError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ObjectError::NotAnObject => write!(f, "Not an object file"),
+            ObjectError::UnsupportedVersion(version) => write!(
+                f,
+                "Object file version {version} is not supported, only {VERSION} is"
+            ),
+            ObjectError::Truncated => write!(f, "The object file ends early"),
+            ObjectError::TrailingBytes => write!(f, "The object file goes on after its end"),
+            ObjectError::InvalidWidth(bits) => {
+                write!(f, "A width of {bits} bits is not from 1 to {}", Width::MAX)
+            }
+            ObjectError::TooFewRegisters(registers) => write!(
+                f,
+                "A machine with {registers} registers is too small, it needs {MIN_REGISTERS}"
+            ),
+            ObjectError::Instructions(error) => write!(f, "{error}"),
+            ObjectError::InvalidDebugInfo => write!(f, "Invalid debug section"),
+        }
+    }
+}
+
+impl Object {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend(VERSION.to_le_bytes());
+        bytes.push(self.width.bits());
+        bytes.push(self.registers);
+        bytes.extend((self.program.len() as u32).to_le_bytes());
+        bytes.extend(encoding::encode_program(&self.program));
+        match &self.debug {
+            Some(debug) => {
+                bytes.push(1);
+                bytes.extend((debug.source.len() as u32).to_le_bytes());
+                bytes.extend(debug.source.as_bytes());
+                for span in &debug.spans {
+                    let (offset, len) =
+                        span.map_or((NO_SPAN, 0), |span| (span.offset as u32, span.len as u32));
+                    bytes.extend(offset.to_le_bytes());
+                    bytes.extend(len.to_le_bytes());
+                }
+            }
+            None => bytes.push(0),
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ObjectError> {
+        let mut reader = Reader(bytes);
+        if reader.take(MAGIC.len()).ok() != Some(&MAGIC[..]) {
+            return Err(ObjectError::NotAnObject);
+        }
+        let version = u16::from_le_bytes(reader.array()?);
+        if version != VERSION {
+            return Err(ObjectError::UnsupportedVersion(version));
+        }
+        let [bits] = reader.array()?;
+        let width = Width::new(bits).ok_or(ObjectError::InvalidWidth(bits))?;
+        let [registers] = reader.array()?;
+        if registers < MIN_REGISTERS {
+            return Err(ObjectError::TooFewRegisters(registers));
+        }
+        let count = reader.u32()? as usize;
+        let image = reader.take(count.saturating_mul(INSTRUCTION_BYTES))?;
+        let program = encoding::decode_program(image).map_err(ObjectError::Instructions)?;
+        let debug = match reader.array()? {
+            [0] => None,
+            [1] => {
+                let len = reader.u32()? as usize;
+                let source = std::str::from_utf8(reader.take(len)?)
+                    .map_err(|_| ObjectError::InvalidDebugInfo)?
+                    .to_owned();
+                let spans = (0..count)
+                    .map(|_| {
+                        let (offset, len) = (reader.u32()?, reader.u32()?);
+                        if offset == NO_SPAN {
+                            return Ok(None);
+                        }
+                        let span = Span::new(offset as usize, len as usize);
+                        match source.get(span.offset..span.offset + span.len) {
+                            Some(_) => Ok(Some(span)),
+                            None => Err(ObjectError::InvalidDebugInfo),
+                        }
+                    })
+                    .collect::<Result<_, _>>()?;
+                Some(DebugInfo { source, spans })
+            }
+            _ => return Err(ObjectError::InvalidDebugInfo),
+        };
+        if !reader.0.is_empty() {
+            return Err(ObjectError::TrailingBytes);
+        }
+        Ok(Object {
+            width,
+            registers,
+            program,
+            debug,
+        })
+    }
+}
+
+/// Takes bytes off the front of an object file.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], ObjectError> {
+        if len > self.0.len() {
+            return Err(ObjectError::Truncated);
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], ObjectError> {
+        Ok(self.take(N)?.try_into().expect("took exactly N bytes"))
+    }
+
+    fn u32(&mut self) -> Result<u32, ObjectError> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+}
@@ -0,0 +1,124 @@
+//! Peephole optimization of linked programs.
+//!
+//! Pairs of neighbouring instructions that one instruction does the work of are replaced by that
+//! instruction, over and over until there are none left. The second of a pair is never the target
+//! of a jump, so whichever way the code is reached it does the same, and jumps to the first are
+//! pointed at the replacement.
+
+use crate::execute::{Instruction, Label};
+
+/// `program` with the waste taken out, and the index in `program` every instruction left came from.
+pub fn optimize(program: Vec<Instruction>) -> (Vec<Instruction>, Vec<usize>) {
+    let mut origins: Vec<usize> = (0..program.len()).collect();
+    let mut program = program;
+    loop {
+        let (optimized, kept) = pass(&program);
+        if optimized.len() == program.len() {
+            return (program, origins);
+        }
+        origins = kept.into_iter().map(|index| origins[index]).collect();
+        program = optimized;
+    }
+}
+
+/// One instruction doing what `first` followed by `second` does, if there is one.
+fn combine(first: &Instruction, second: &Instruction) -> Option<Instruction> {
+    match (first, second) {
+        // Copying a value back to where it came from, as into `RESULT_REGISTER` and straight out
+        (
+            &Instruction::Copy { src, dest },
+            &Instruction::Copy {
+                src: back,
+                dest: to,
+            },
+        ) if back == dest && to == src => Some(first.clone()),
+        // Masking a constant, with 0 as well as with the width
+        (
+            &Instruction::StoreI { constant, register },
+            &Instruction::AndI {
+                register: masked,
+                constant: mask,
+            },
+        ) if masked == register => Some(Instruction::StoreI {
+            constant: constant & mask,
+            register,
+        }),
+        // Storing 0 in a register that was just cleared
+        (
+            &Instruction::AndI {
+                register,
+                constant: 0,
+            },
+            &Instruction::StoreI {
+                constant: 0,
+                register: stored,
+            },
+        ) if stored == register => Some(Instruction::StoreI {
+            constant: 0,
+            register,
+        }),
+        // A constant overwritten by another before anything reads it
+        (
+            &Instruction::StoreI { register, .. },
+            &Instruction::StoreI {
+                register: stored, ..
+            },
+        ) if stored == register => Some(second.clone()),
+        // Loading a spilled value straight back into the register it was spilled from
+        (
+            &Instruction::Store { register, slot },
+            &Instruction::Load {
+                slot: from,
+                register: to,
+            },
+        ) if from == slot && to == register => Some(first.clone()),
+        _ => None,
+    }
+}
+
+/// `program` with the pairs `combine` finds replaced from the start on, and the index in `program`
+/// each instruction came from.
+fn pass(program: &[Instruction]) -> (Vec<Instruction>, Vec<usize>) {
+    let mut targets = vec![false; program.len() + 1];
+    for target in program.iter().filter_map(Instruction::target) {
+        if let Some(target) = targets.get_mut(target as usize) {
+            *target = true;
+        }
+    }
+    let mut optimized = Vec::with_capacity(program.len());
+    let mut kept = Vec::with_capacity(program.len());
+    // Where each instruction of `program` ends up, and one past the end
+    let mut moved = Vec::with_capacity(program.len() + 1);
+    let mut index = 0;
+    while index < program.len() {
+        moved.push(optimized.len() as Label);
+        let combined = program
+            .get(index + 1)
+            .filter(|_| !targets[index + 1])
+            .and_then(|second| combine(&program[index], second));
+        kept.push(index);
+        match combined {
+            Some(instruction) => {
+                moved.push(optimized.len() as Label);
+                optimized.push(instruction);
+                index += 2;
+            }
+            None => {
+                optimized.push(program[index].clone());
+                index += 1;
+            }
+        }
+    }
+    moved.push(optimized.len() as Label);
+    let removed = (program.len() - optimized.len()) as Label;
+    for instruction in &mut optimized {
+        if let Some(target) = instruction.target_mut() {
+            *target = match moved.get(*target as usize) {
+                Some(&moved) => moved,
+                // Past the end of the program, where it stays
+                None => *target - removed,
+            };
+        }
+    }
+    (optimized, kept)
+}
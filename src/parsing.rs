@@ -1,165 +1,216 @@
-use crate::parsing::Node::{DivN, MulN, NumberN, Temp};
-use crate::parsing::Parser::{
-    Digit, DivE, DivP, Exactly, Expr, If, MulE, MulP, Number, Or, Repeat, Then,
+use crate::diagnostic::{Diagnostic, Span};
+use crate::execute::RegisterContent;
+use crate::parsing::Node::{AddN, DivN, ModN, MulN, NumberN, SubN, Temp};
+use crate::parsing::Parser::{Atom, Digit, Expr, Number, Product, Repeat, Sum};
+use crate::tokens::TokenKind::{
+    self, AddT, DigitT, DivT, LParenT, ModT, MulT, PrefixT, RParenT, SubT,
 };
-use crate::tokens::Token;
-use crate::tokens::Token::{DivT, MulT, One, Zero};
+use crate::tokens::{Radix, Token};
+use crate::width::Width;
 
 //Parsing
 #[derive(Clone, Debug)]
 pub enum Parser {
-    Or(Box<Self>, Box<Self>),
-    Then(Box<Self>, Box<Self>),
-    Exactly(u8, Box<Self>),
+    Repeat(Box<Self>),
     Digit,
     Number,
     Expr,
-    MulP,
-    DivP,
-    MulE,
-    DivE,
-    If {
-        pred: fn(Vec<Token>, &[Node]) -> bool,
-        parser: Box<Self>,
-    },
-    Repeat(Box<Self>),
+    Sum,
+    Product,
+    Atom,
 }
 
+type BuildNode = fn(Box<Node>, Box<Node>) -> Node;
+
 impl Parser {
     pub(crate) fn parse(
         &self,
         tokens: Vec<Token>,
         nodes: &[Node],
-    ) -> Result<(Vec<Token>, Vec<Node>), String> {
+        width: Width,
+    ) -> Result<(Vec<Token>, Vec<Node>), Diagnostic> {
         match self {
-            Repeat(parser) => {
-                let mut prev = (tokens, nodes.to_vec());
-                let mut next = parser.parse(prev.0.clone(), &prev.1)?;
-                while !prev.0.is_empty() && next.0.len() < prev.0.len() {
-                    prev = (next.0.clone(), next.1);
-                    next = parser.parse(prev.0.clone(), &prev.1)?;
-                }
-                Ok((prev.0, prev.1.to_vec()))
-            }
-            Then(first, second) => match first.parse(tokens.clone(), nodes)? {
-                (first_ts, first_ns) if first_ts.len() < tokens.len() => {
-                    match second.parse(first_ts.clone(), &first_ns)? {
-                        (second_ts, second_ns) if second_ts.len() < first_ts.len() => {
-                            Ok((second_ts, second_ns.to_vec()))
-                        }
-                        _ => Ok((tokens, nodes.to_vec())),
+            Expr => Sum.parse(tokens, nodes, width),
+            Sum => chain(
+                tokens,
+                nodes,
+                width,
+                &Product,
+                &[
+                    (AddT, |lhs, rhs| AddN { lhs, rhs }),
+                    (SubT, |lhs, rhs| SubN { lhs, rhs }),
+                ],
+            ),
+            Product => chain(
+                tokens,
+                nodes,
+                width,
+                &Atom,
+                &[
+                    (MulT, |lhs, rhs| MulN { lhs, rhs }),
+                    (DivT, |lhs, rhs| DivN { lhs, rhs }),
+                    (ModT, |lhs, rhs| ModN { lhs, rhs }),
+                ],
+            ),
+            Atom => match tokens.first() {
+                Some(&open @ Token { kind: LParenT, .. }) => {
+                    let rest = match Expr.parse(tokens[1..].to_vec(), nodes, width)? {
+                        (new_ts, new_ns) if new_ts.len() < tokens.len() - 1 => (new_ts, new_ns),
+                        _ => return Err(expected_operand(&tokens[1..], open)),
+                    };
+                    match rest.0.first() {
+                        Some(Token { kind: RParenT, .. }) => Ok((rest.0[1..].to_vec(), rest.1)),
+                        Some(token) => Err(Diagnostic::new("Expected ')'", token.span)),
+                        None => Err(Diagnostic::new("Unmatched '('", open.span)),
                     }
                 }
-                _ => Ok((tokens, nodes.to_vec())),
-            },
-            Expr => Repeat(Box::new(Or(Box::new(DivE), Box::new(MulE)))).parse(tokens, nodes), //Inner or empty but eh.
-            DivP => match tokens[..] {
-                [DivT, ..] => Ok((tokens[1..].to_vec(), nodes.to_vec())),
-                _ => Ok((tokens, nodes.to_vec())),
-            },
-            MulP => match tokens[..] {
-                [MulT, ..] => Ok((tokens[1..].to_vec(), nodes.to_vec())),
-                _ => Ok((tokens, nodes.to_vec())),
+                _ => Number.parse(tokens, nodes, width),
             },
-            Number => match Exactly(4, Box::new(Digit)).parse(tokens.clone(), nodes)? {
-                (new_tokens, new_ns) if new_ns.len() >= 4 && new_tokens.len() < tokens.len() => {
-                    let mut new_nodes = vec![NumberN(to_i16(&new_ns[0..4])?)];
-                    new_nodes.append(&mut nodes.to_vec());
-                    Ok((new_tokens, new_nodes))
-                }
-                _ => Ok((tokens, nodes.to_vec())),
-            },
-            Or(first, second) => match first.parse(tokens.clone(), nodes)? {
-                (new_tokens, new_nodes) if new_tokens.len() < tokens.len() => {
-                    Ok((new_tokens, new_nodes))
-                }
-                _ => second.parse(tokens, nodes),
-            },
-            Exactly(amount, parser) => {
-                let mut next = (tokens.clone(), nodes.to_vec());
-                for _ in 0..*amount {
-                    match parser.parse(next.0.clone(), &next.1)? {
-                        (new_tokens, new_nodes) if new_tokens.len() < next.0.len() => {
-                            next = (new_tokens, new_nodes)
+            Number => {
+                let (radix, digits) = match tokens.first() {
+                    Some(
+                        &prefix @ Token {
+                            kind: PrefixT(radix),
+                            ..
+                        },
+                    ) => match tokens.get(1) {
+                        Some(Token {
+                            kind: DigitT(_),
+                            span,
+                        }) if span.offset == prefix.span.offset + prefix.span.len => {
+                            (Some(radix), &tokens[1..])
                         }
-                        _ => return Ok((tokens, nodes.to_vec())),
+                        _ => return Err(Diagnostic::new("Expected digits", prefix.span)),
+                    },
+                    _ => (None, &tokens[..]),
+                };
+                match Repeat(Box::new(Digit)).parse(digits.to_vec(), nodes, width)? {
+                    (new_tokens, new_ns) if new_tokens.len() < digits.len() => {
+                        let consumed = digits.len() - new_tokens.len();
+                        let span = tokens[0].span.to(digits[consumed - 1].span);
+                        let value = to_number(&new_ns[0..consumed], radix, width, span)?;
+                        let mut new_nodes = vec![NumberN(value, span)];
+                        new_nodes.append(&mut nodes.to_vec());
+                        Ok((new_tokens, new_nodes))
                     }
+                    _ => Ok((tokens, nodes.to_vec())),
+                }
+            }
+            Repeat(parser) => {
+                let mut prev = (tokens, nodes.to_vec());
+                let mut next = parser.parse(prev.0.clone(), &prev.1, width)?;
+                while !prev.0.is_empty() && next.0.len() < prev.0.len() {
+                    prev = (next.0.clone(), next.1);
+                    next = parser.parse(prev.0.clone(), &prev.1, width)?;
                 }
-                Ok(next)
+                Ok(prev)
             }
             Digit => {
                 if tokens.is_empty() {
                     return Ok((tokens, nodes.to_vec()));
                 }
-                match tokens[0] {
-                    Zero => {
-                        let mut new_nodes = vec![Temp(0)];
-                        new_nodes.append(&mut nodes.to_vec());
-                        Ok((tokens[1..].to_vec(), new_nodes))
+                // Digits of one literal are written without anything in between
+                if let Some(Temp(_, previous)) = nodes.first() {
+                    if previous.offset + previous.len != tokens[0].span.offset {
+                        return Ok((tokens, nodes.to_vec()));
                     }
-                    One => {
-                        let mut new_nodes = vec![Temp(1)];
+                }
+                match tokens[0].kind {
+                    DigitT(digit) => {
+                        let mut new_nodes = vec![Temp(digit as i8, tokens[0].span)];
                         new_nodes.append(&mut nodes.to_vec());
                         Ok((tokens[1..].to_vec(), new_nodes))
                     }
                     _ => Ok((tokens, nodes.to_vec())),
                 }
             }
-            MulE => op(tokens, nodes, false),
-            DivE => op(tokens, nodes, true),
-            If { pred, parser } => {
-                if pred(tokens.clone(), nodes) {
-                    parser.parse(tokens, nodes)
-                } else {
-                    Ok((tokens, nodes.to_vec()))
-                }
-            }
         }
     }
 }
 
-fn op(tokens: Vec<Token>, nodes: &[Node], div: bool) -> Result<(Vec<Token>, Vec<Node>), String> {
-    if tokens.is_empty() {
-        return Ok((tokens, nodes.to_vec()));
-    }
+/// `operand (op operand)*`, folded left-associatively into the node built for each `op`.
+fn chain(
+    tokens: Vec<Token>,
+    nodes: &[Node],
+    width: Width,
+    operand: &Parser,
+    ops: &[(TokenKind, BuildNode)],
+) -> Result<(Vec<Token>, Vec<Node>), Diagnostic> {
+    let (mut rest, mut new_ns) = match operand.parse(tokens.clone(), nodes, width)? {
+        (new_ts, new_ns) if new_ts.len() < tokens.len() => (new_ts, new_ns),
+        _ => return Ok((tokens, nodes.to_vec())),
+    };
 
-    let parser = if div { DivP } else { MulP };
-    match Then(
-        Box::new(Or(
-            Box::new(If {
-                pred: |_, y| matches!(y.first(), Some(MulN { .. }) | Some(DivN { .. })),
-                parser: Box::new(parser.clone()),
-            }),
-            Box::new(Then(Box::new(Number), Box::new(parser))),
-        )),
-        Box::new(Number),
-    )
-    .parse(tokens.clone(), nodes)?
-    {
-        (new_ts, new_ns) if new_ts.len() < tokens.len() => {
-            let lhs = Box::new(new_ns[0].clone());
-            let rhs = Box::new(new_ns[1].clone());
-            let node = if div {
-                DivN { rhs: lhs, lhs: rhs }
-            } else {
-                MulN { rhs: lhs, lhs: rhs }
-            };
-            let mut newer_ns = vec![node];
-            newer_ns.append(&mut new_ns[2..].to_vec());
-            Ok((new_ts, newer_ns))
+    while let Some(op) = rest.first().copied() {
+        let Some((_, build)) = ops.iter().find(|(kind, _)| *kind == op.kind) else {
+            break;
+        };
+        match operand.parse(rest[1..].to_vec(), &new_ns, width)? {
+            (new_ts, newer_ns) if new_ts.len() < rest.len() - 1 => {
+                let lhs = Box::new(newer_ns[1].clone());
+                let rhs = Box::new(newer_ns[0].clone());
+                new_ns = vec![build(lhs, rhs)];
+                new_ns.extend_from_slice(&newer_ns[2..]);
+                rest = new_ts;
+            }
+            _ => return Err(expected_operand(&rest[1..], op)),
         }
-        _ => Ok((tokens, nodes.to_vec())),
     }
+    Ok((rest, new_ns))
 }
 
-fn to_i16(inp: &[Node]) -> Result<i16, &'static str> {
-    let mut res = 0;
-    for x in inp {
-        res >>= 1;
+/// Points at whatever follows `after` in place of the operand that should be there.
+fn expected_operand(rest: &[Token], after: Token) -> Diagnostic {
+    let span = match rest.first() {
+        Some(token) => token.span,
+        None => Span::new(after.span.offset + after.span.len, 0),
+    };
+    Diagnostic::new("Expected an operand", span)
+}
+
+/// Value of the digits in `inp`, which holds the last digit first. Without a prefix a literal is
+/// binary, unless it has digits other than 0 and 1 which makes it decimal.
+fn to_number(
+    inp: &[Node],
+    radix: Option<Radix>,
+    width: Width,
+    span: Span,
+) -> Result<RegisterContent, Diagnostic> {
+    let mut digits = Vec::with_capacity(inp.len());
+    for x in inp.iter().rev() {
         match x {
-            Temp(0) => {}
-            Temp(1) => res += 8,
-            _ => return Err("Uh no"),
+            Temp(digit, digit_span) => digits.push((*digit as RegisterContent, *digit_span)),
+            _ => return Err(Diagnostic::new("Uh no, not a digit", span)),
+        }
+    }
+
+    let radix = match radix {
+        Some(radix) => radix,
+        None if digits.iter().all(|(digit, _)| *digit <= 1) => Radix::Binary,
+        None if digits[0].0 == 0 => {
+            return Err(Diagnostic::new(
+                "Decimal literals starting with 0 need a 0d prefix",
+                span,
+            ))
+        }
+        None => Radix::Decimal,
+    };
+    let base = radix.base() as RegisterContent;
+
+    let mut res = 0;
+    for (digit, digit_span) in digits {
+        if digit >= base {
+            return Err(Diagnostic::new(
+                format!("Not a base {base} digit"),
+                digit_span,
+            ));
+        }
+        res = res * base + digit;
+        if res > width.mask() {
+            return Err(Diagnostic::new(
+                format!("Literal does not fit in {} bits", width.bits()),
+                span,
+            ));
         }
     }
     Ok(res)
@@ -167,8 +218,25 @@ fn to_i16(inp: &[Node]) -> Result<i16, &'static str> {
 
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum Node {
-    NumberN(i16),
+    NumberN(RegisterContent, Span),
     MulN { lhs: Box<Self>, rhs: Box<Self> },
     DivN { lhs: Box<Self>, rhs: Box<Self> },
-    Temp(i8),
+    ModN { lhs: Box<Self>, rhs: Box<Self> },
+    AddN { lhs: Box<Self>, rhs: Box<Self> },
+    SubN { lhs: Box<Self>, rhs: Box<Self> },
+    Temp(i8, Span),
+}
+
+impl Node {
+    /// From the first literal to the last, parentheses around the ends are not included.
+    pub fn span(&self) -> Span {
+        match self {
+            NumberN(_, span) | Temp(_, span) => *span,
+            MulN { lhs, rhs }
+            | DivN { lhs, rhs }
+            | ModN { lhs, rhs }
+            | AddN { lhs, rhs }
+            | SubN { lhs, rhs } => lhs.span().to(rhs.span()),
+        }
+    }
 }
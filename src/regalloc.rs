@@ -0,0 +1,335 @@
+use crate::compile::RESULT_REGISTER;
+use crate::execute::{Instruction, Label, Register, Slot};
+use std::collections::HashMap;
+use std::ops::Range;
+
+pub type VirtualRegister = u16;
+
+/// Physical registers kept free for reloading spilled values around a single instruction, no
+/// instruction reads more than two registers.
+const SCRATCH_REGISTERS: u8 = 2;
+/// Fewest registers a `Machine` needs to run allocated code, the answer goes in `RESULT_REGISTER`.
+pub const MIN_REGISTERS: u8 = RESULT_REGISTER + 1;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Location {
+    Register(Register),
+    Spilled(Slot),
+}
+
+/// Range of instruction indices over which a virtual register holds a value that is still needed.
+#[derive(Debug)]
+struct Interval {
+    register: VirtualRegister,
+    start: usize,
+    end: usize,
+}
+
+/// Set of virtual registers, one bit each.
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct LiveSet(Vec<u64>);
+
+impl LiveSet {
+    fn new(registers: usize) -> Self {
+        Self(vec![0; registers.div_ceil(64)])
+    }
+
+    fn insert(&mut self, register: VirtualRegister) {
+        self.0[register as usize / 64] |= 1 << (register % 64);
+    }
+
+    fn remove(&mut self, register: VirtualRegister) {
+        self.0[register as usize / 64] &= !(1 << (register % 64));
+    }
+
+    fn union(&mut self, other: &LiveSet) {
+        for (word, other) in self.0.iter_mut().zip(&other.0) {
+            *word |= other;
+        }
+    }
+
+    fn iter(&self) -> impl Iterator<Item = VirtualRegister> + '_ {
+        self.0.iter().enumerate().flat_map(|(index, &word)| {
+            let mut rest = word;
+            std::iter::from_fn(move || {
+                let bit = rest.trailing_zeros();
+                rest &= rest.checked_sub(1)?;
+                Some((index * 64 + bit as usize) as VirtualRegister)
+            })
+        })
+    }
+}
+
+/// Maps the virtual registers of `program` onto `registers` physical ones by linear scan,
+/// spilling to memory when they run out, and moves each of the `results` into the physical
+/// register paired with it at the end.
+///
+/// Also returns the index in `program` every allocated instruction was made for, the moves at the
+/// end count as `program.len()`. `None` when the allocated program is too long for its end to
+/// have a `Label`.
+pub fn allocate(
+    program: Vec<Instruction<VirtualRegister>>,
+    results: &[(VirtualRegister, Register)],
+    registers: u8,
+) -> Option<(Vec<Instruction>, Vec<usize>)> {
+    assert!(
+        registers >= MIN_REGISTERS,
+        "Need at least {MIN_REGISTERS} registers"
+    );
+
+    let intervals = intervals(&program, results);
+    let mut locations = scan(&intervals, 0..registers);
+    if locations
+        .values()
+        .any(|location| matches!(location, Location::Spilled(_)))
+    {
+        locations = scan(&intervals, SCRATCH_REGISTERS..registers);
+    }
+    let end = program.len();
+    let (mut allocated, starts) = rewrite(program, &locations)?;
+    let mut origins: Vec<usize> = starts
+        .windows(2)
+        .enumerate()
+        .flat_map(|(index, start)| std::iter::repeat_n(index, (start[1] - start[0]) as usize))
+        .collect();
+    move_results(&mut allocated, &locations, results);
+    Label::try_from(allocated.len()).ok()?;
+    origins.resize(allocated.len(), end);
+    Some((allocated, origins))
+}
+
+/// Live ranges of every virtual register, sorted by start.
+fn intervals(
+    program: &[Instruction<VirtualRegister>],
+    results: &[(VirtualRegister, Register)],
+) -> Vec<Interval> {
+    let end = program.len();
+    let successors = |index: usize| {
+        let instruction = &program[index];
+        let next = instruction.falls_through().then_some(index + 1);
+        let jump = instruction.target().map(|target| target as usize);
+        next.into_iter().chain(jump).map(move |s| s.min(end))
+    };
+
+    // Backwards dataflow until nothing changes, loops need more than one pass.
+    let count = program
+        .iter()
+        .flat_map(|instruction| instruction.reads().into_iter().chain(instruction.writes()))
+        .chain(results.iter().map(|(result, _)| *result))
+        .max()
+        .map_or(0, |register| register as usize + 1);
+    let mut live_in = vec![LiveSet::new(count); end + 1];
+    for (result, _) in results {
+        live_in[end].insert(*result);
+    }
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for index in (0..end).rev() {
+            let mut live = LiveSet::new(count);
+            for successor in successors(index) {
+                live.union(&live_in[successor]);
+            }
+            if let Some(written) = program[index].writes() {
+                live.remove(written);
+            }
+            for read in program[index].reads() {
+                live.insert(read);
+            }
+            if live != live_in[index] {
+                live_in[index] = live;
+                changed = true;
+            }
+        }
+    }
+
+    let mut ranges: Vec<Option<(usize, usize)>> = vec![None; count];
+    for (index, instruction) in program.iter().enumerate() {
+        let live = live_in[index].iter().chain(instruction.writes());
+        for register in live {
+            let range = ranges[register as usize].get_or_insert((index, index));
+            range.0 = range.0.min(index);
+            range.1 = range.1.max(index);
+        }
+    }
+
+    let mut intervals: Vec<Interval> = ranges
+        .into_iter()
+        .enumerate()
+        .filter_map(|(register, range)| {
+            range.map(|(start, end)| Interval {
+                register: register as VirtualRegister,
+                start,
+                end,
+            })
+        })
+        .collect();
+    intervals.sort_by_key(|interval| (interval.start, interval.register));
+    intervals
+}
+
+/// Linear scan, spilling whichever interval ends last when `registers` are all taken.
+fn scan(intervals: &[Interval], registers: Range<u8>) -> HashMap<VirtualRegister, Location> {
+    let mut free: Vec<Register> = registers.rev().collect();
+    let mut active: Vec<&Interval> = Vec::new();
+    let mut locations = HashMap::new();
+    let mut next_slot: Slot = 0;
+
+    for interval in intervals {
+        active.retain(|other| {
+            if other.end < interval.start {
+                if let Some(Location::Register(register)) = locations.get(&other.register) {
+                    free.push(*register);
+                }
+                false
+            } else {
+                true
+            }
+        });
+        free.sort_unstable_by(|a, b| b.cmp(a));
+
+        if let Some(register) = free.pop() {
+            locations.insert(interval.register, Location::Register(register));
+            active.push(interval);
+            continue;
+        }
+
+        let (furthest, _) = active
+            .iter()
+            .enumerate()
+            .max_by_key(|(_, other)| other.end)
+            .expect("registers to be in use when none are free");
+        if active[furthest].end > interval.end {
+            let spilled = active.swap_remove(furthest);
+            let register = locations[&spilled.register];
+            locations.insert(spilled.register, Location::Spilled(next_slot));
+            locations.insert(interval.register, register);
+            active.push(interval);
+        } else {
+            locations.insert(interval.register, Location::Spilled(next_slot));
+        }
+        next_slot += 1;
+    }
+    locations
+}
+
+/// Replaces virtual registers by their locations, reloading spilled ones into scratch registers
+/// around each instruction and moving jump targets past the inserted code. Also returns where the
+/// code for each instruction starts, and where it all ends. `None` if that is past the last
+/// `Label`.
+fn rewrite(
+    program: Vec<Instruction<VirtualRegister>>,
+    locations: &HashMap<VirtualRegister, Location>,
+) -> Option<(Vec<Instruction>, Vec<Label>)> {
+    let mut allocated = Vec::with_capacity(program.len());
+    let mut starts = Vec::with_capacity(program.len() + 1);
+
+    for instruction in program {
+        starts.push(Label::try_from(allocated.len()).ok()?);
+
+        let mut scratch: Vec<(VirtualRegister, Register)> = Vec::new();
+        for register in instruction.reads() {
+            if let Location::Spilled(slot) = locations[&register] {
+                if scratch.iter().all(|(spilled, _)| *spilled != register) {
+                    let into = scratch.len() as Register;
+                    scratch.push((register, into));
+                    allocated.push(Instruction::Load {
+                        slot,
+                        register: into,
+                    });
+                }
+            }
+        }
+        // Reads happen before the write, so the first scratch register is free again by then.
+        let store = instruction.writes().and_then(|register| {
+            let Location::Spilled(slot) = locations[&register] else {
+                return None;
+            };
+            if scratch.iter().all(|(spilled, _)| *spilled != register) {
+                scratch.push((register, 0));
+            }
+            Some((register, slot))
+        });
+
+        allocated.push(instruction.map_registers(|register| {
+            match locations[&register] {
+                Location::Register(physical) => physical,
+                Location::Spilled(_) => scratch
+                    .iter()
+                    .rev()
+                    .find(|(spilled, _)| *spilled == register)
+                    .map(|(_, physical)| *physical)
+                    .expect("spilled register to be reloaded"),
+            }
+        }));
+
+        if let Some((register, slot)) = store {
+            let from = scratch
+                .iter()
+                .rev()
+                .find(|(spilled, _)| *spilled == register)
+                .map(|(_, physical)| *physical)
+                .expect("spilled register to be assigned");
+            allocated.push(Instruction::Store {
+                register: from,
+                slot,
+            });
+        }
+    }
+    starts.push(Label::try_from(allocated.len()).ok()?);
+
+    for instruction in &mut allocated {
+        if let Some(target) = instruction.target_mut() {
+            *target = starts[*target as usize];
+        }
+    }
+    Some((allocated, starts))
+}
+
+/// Copies the results into their registers without one overwriting another before it has moved.
+/// Only the results are live at the end, so any other register can break a cycle.
+fn move_results(
+    allocated: &mut Vec<Instruction>,
+    locations: &HashMap<VirtualRegister, Location>,
+    results: &[(VirtualRegister, Register)],
+) {
+    let mut copies = Vec::new();
+    let mut loads = Vec::new();
+    for &(result, dest) in results {
+        match locations[&result] {
+            Location::Register(src) if src == dest => {}
+            Location::Register(src) => copies.push((src, dest)),
+            Location::Spilled(slot) => loads.push((slot, dest)),
+        }
+    }
+
+    while !copies.is_empty() {
+        let ready = copies
+            .iter()
+            .position(|&(_, dest)| copies.iter().all(|&(src, _)| src != dest));
+        match ready {
+            Some(index) => {
+                let (src, dest) = copies.remove(index);
+                allocated.push(Instruction::Copy { src, dest });
+            }
+            None => {
+                let free = (0..)
+                    .find(|register| {
+                        copies
+                            .iter()
+                            .all(|&(src, dest)| src != *register && dest != *register)
+                    })
+                    .expect("a register outside the cycle");
+                allocated.push(Instruction::Copy {
+                    src: copies[0].0,
+                    dest: free,
+                });
+                copies[0].0 = free;
+            }
+        }
+    }
+
+    for (slot, register) in loads {
+        allocated.push(Instruction::Load { slot, register });
+    }
+}
@@ -1,27 +1,123 @@
-use crate::tokens::Token::{DivT, MulT, One, Zero};
+use crate::diagnostic::{Diagnostic, Span};
+use crate::tokens::TokenKind::{AddT, DigitT, DivT, LParenT, ModT, MulT, PrefixT, RParenT, SubT};
+
+/// How `tokenize` treats characters that are not part of the language.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Mode {
+    /// Skip whitespace, report anything else that is unknown.
+    Strict,
+    /// Silently drop anything unknown.
+    Lenient,
+}
 
 //Tokenize
-pub fn tokenize(inp: &str) -> Result<Vec<Token>, &'static str> {
-    if !inp.is_ascii() {
-        return Err("I only know ascii 😀");
+pub fn tokenize(inp: &str, mode: Mode) -> Result<Vec<Token>, Vec<Diagnostic>> {
+    if mode == Mode::Lenient {
+        if let Some((offset, c)) = inp.char_indices().find(|(_, c)| !c.is_ascii()) {
+            return Err(vec![Diagnostic::new(
+                "I only know ascii 😀",
+                Span::new(offset, c.len_utf8()),
+            )]);
+        }
     }
 
-    Ok(inp
-        .chars()
-        .filter_map(|c| match c {
-            '1' => Some(One),
-            '0' => Some(Zero),
-            '/' => Some(DivT),
-            '*' => Some(MulT),
-            _ => None,
-        })
-        .collect())
+    let mut tokens: Vec<Token> = Vec::new();
+    let mut errors = Vec::new();
+    let mut chars = inp.char_indices().peekable();
+    // Letters are only digits straight after a `0x` and the digits that follow it
+    let mut in_hex = false;
+    while let Some((offset, c)) = chars.next() {
+        // A prefix only starts a literal, in `0x0b` the `b` is a digit
+        let starts_literal = !inp[..offset].ends_with(|c: char| c.is_ascii_alphanumeric());
+        let radix = chars.peek().and_then(|(_, next)| Radix::from_prefix(*next));
+        if let (true, '0', Some(radix)) = (starts_literal, c, radix) {
+            chars.next();
+            tokens.push(Token {
+                kind: PrefixT(radix),
+                span: Span::new(offset, 2),
+            });
+            in_hex = radix == Radix::Hexadecimal;
+            continue;
+        }
+
+        let digit = c.is_ascii_digit() || in_hex && c.is_ascii_hexdigit();
+        in_hex &= digit;
+        let kind = match c {
+            _ if digit => DigitT(c.to_digit(16).unwrap() as u8),
+            '/' => DivT,
+            '%' => ModT,
+            '*' => MulT,
+            '+' => AddT,
+            '-' => SubT,
+            '(' => LParenT,
+            ')' => RParenT,
+            _ if mode == Mode::Lenient || c.is_whitespace() => continue,
+            _ => {
+                errors.push(Diagnostic::new(
+                    format!("Unexpected character {c:?}"),
+                    Span::new(offset, c.len_utf8()),
+                ));
+                continue;
+            }
+        };
+        tokens.push(Token {
+            kind,
+            span: Span::new(offset, 1),
+        });
+    }
+
+    if errors.is_empty() {
+        Ok(tokens)
+    } else {
+        Err(errors)
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub span: Span,
 }
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
-pub enum Token {
-    Zero,
-    One,
+pub enum TokenKind {
+    DigitT(u8),
+    PrefixT(Radix),
     DivT,
+    ModT,
     MulT,
+    AddT,
+    SubT,
+    LParenT,
+    RParenT,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Radix {
+    Binary,
+    Octal,
+    Decimal,
+    Hexadecimal,
+}
+
+impl Radix {
+    /// The radix for the letter after the `0` of a prefix like `0x`.
+    fn from_prefix(letter: char) -> Option<Self> {
+        match letter.to_ascii_lowercase() {
+            'b' => Some(Radix::Binary),
+            'o' => Some(Radix::Octal),
+            'd' => Some(Radix::Decimal),
+            'x' => Some(Radix::Hexadecimal),
+            _ => None,
+        }
+    }
+
+    pub fn base(self) -> u8 {
+        match self {
+            Radix::Binary => 2,
+            Radix::Octal => 8,
+            Radix::Decimal => 10,
+            Radix::Hexadecimal => 16,
+        }
+    }
 }
@@ -0,0 +1,111 @@
+use crate::execute::{Instruction, Label, RegisterContent};
+
+/// Hooks a `Machine` calls while it runs, every one of them does nothing unless overridden.
+pub trait Tracer {
+    /// `instruction` at `pc` is about to run on `registers`.
+    fn before(&mut self, _pc: Label, _instruction: &Instruction, _registers: &[RegisterContent]) {}
+
+    /// `instruction` at `pc` ran and left `registers` behind.
+    fn after(&mut self, _pc: Label, _instruction: &Instruction, _registers: &[RegisterContent]) {}
+
+    /// The jump at `from` was taken, called between `before` and `after`.
+    fn jump(&mut self, _from: Label, _to: Label) {}
+
+    /// The program ran to its end after `steps` instructions.
+    fn halt(&mut self, _steps: u64) {}
+}
+
+impl<T: Tracer + ?Sized> Tracer for &mut T {
+    fn before(&mut self, pc: Label, instruction: &Instruction, registers: &[RegisterContent]) {
+        (**self).before(pc, instruction, registers)
+    }
+
+    fn after(&mut self, pc: Label, instruction: &Instruction, registers: &[RegisterContent]) {
+        (**self).after(pc, instruction, registers)
+    }
+
+    fn jump(&mut self, from: Label, to: Label) {
+        (**self).jump(from, to)
+    }
+
+    fn halt(&mut self, steps: u64) {
+        (**self).halt(steps)
+    }
+}
+
+/// Traces nothing.
+#[derive(Debug, Default)]
+pub struct NoTracer;
+
+impl Tracer for NoTracer {}
+
+/// Prints a row to stdout for every instruction, with each register in binary.
+#[derive(Debug, Default)]
+pub struct TableTracer;
+
+impl Tracer for TableTracer {
+    fn after(&mut self, _pc: Label, instruction: &Instruction, registers: &[RegisterContent]) {
+        let registers: Vec<String> = registers
+            .iter()
+            .map(|register| format!("{register:08b}"))
+            .collect();
+        println!(
+            "| {:<20}| {} |",
+            instruction.to_string(),
+            registers.join(" | ")
+        );
+    }
+}
+
+/// Something a `CollectingTracer` saw.
+#[cfg(test)]
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Event {
+    Before {
+        pc: Label,
+        instruction: Instruction,
+    },
+    After {
+        pc: Label,
+        registers: Vec<RegisterContent>,
+    },
+    Jump {
+        from: Label,
+        to: Label,
+    },
+    Halt {
+        steps: u64,
+    },
+}
+
+/// Keeps every event in memory.
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub struct CollectingTracer {
+    pub events: Vec<Event>,
+}
+
+#[cfg(test)]
+impl Tracer for CollectingTracer {
+    fn before(&mut self, pc: Label, instruction: &Instruction, _registers: &[RegisterContent]) {
+        self.events.push(Event::Before {
+            pc,
+            instruction: instruction.clone(),
+        });
+    }
+
+    fn after(&mut self, pc: Label, _instruction: &Instruction, registers: &[RegisterContent]) {
+        self.events.push(Event::After {
+            pc,
+            registers: registers.to_vec(),
+        });
+    }
+
+    fn jump(&mut self, from: Label, to: Label) {
+        self.events.push(Event::Jump { from, to });
+    }
+
+    fn halt(&mut self, steps: u64) {
+        self.events.push(Event::Halt { steps });
+    }
+}
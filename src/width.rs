@@ -0,0 +1,36 @@
+use crate::execute::RegisterContent;
+
+/// Number of bits in an operand. Products take twice as many, restoring division needs one more
+/// on top of that for the sign of the partial remainder, all of which has to fit a register.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Width(u8);
+
+impl Width {
+    pub const MAX: u8 = ((RegisterContent::BITS - 1) / 2) as u8;
+
+    pub fn new(bits: u8) -> Option<Self> {
+        (1..=Self::MAX).contains(&bits).then_some(Self(bits))
+    }
+
+    pub fn bits(self) -> u8 {
+        self.0
+    }
+
+    /// An operand with every bit set.
+    pub fn mask(self) -> RegisterContent {
+        (1 << self.0) - 1
+    }
+
+    /// An answer in binary, as wide as a product of two operands.
+    pub fn format_answer(self, answer: RegisterContent) -> String {
+        let digits = 2 * self.0 as usize;
+        let answer = answer & ((1 << digits) - 1);
+        format!("{answer:0digits$b}")
+    }
+}
+
+impl Default for Width {
+    fn default() -> Self {
+        Self(4)
+    }
+}